@@ -1,8 +1,21 @@
 pub mod core;
+pub mod error;
+pub mod buffer;
+pub mod http;
+#[cfg(test)]
+pub mod test_support;
 
 pub mod log;
+pub mod file;
+pub mod sqlite;
 pub mod mqtt;
 pub mod cloudwatch;
+pub mod prometheus;
+pub mod influxdb;
+pub mod webhook;
 
 pub mod constant;
+pub mod onewire;
+pub mod hwmon;
+pub mod system;
 pub mod bluetooth;
@@ -1,4 +1,8 @@
 pub mod db;
+pub mod decoder;
+pub mod bthome;
+pub mod xiaomi;
+pub mod ruuvi;
 
 use serde::{Serialize, Deserialize};
 
@@ -30,6 +34,8 @@ type PeripheralImp = btleplug::winrtble::peripheral::Peripheral;
 
 
 use btleplug::api::{CentralEvent,BDAddr,PeripheralProperties};
+use decoder::{Advertisement, BLEDecoder};
+use chrono::{DateTime, Utc};
 
 
 pub const DBADDR_ZERO :BDAddr =  BDAddr {
@@ -63,15 +69,37 @@ pub use super::core::*;
 
 use async_trait::async_trait;
 
+/// A device to listen for.  Matched by address, or by advertised name if no address is given.
+#[derive(Deserialize,Serialize)]
+pub struct BLEDeviceConfig {
+    #[serde(default)]
+    address : Option<String>,
+    #[serde(default)]
+    local_name : Option<String>,
+    /// Object name for the metrics, defaults to the address
+    #[serde(default)]
+    object : Option<String>,
+    #[serde(default)]
+    decoder : BLEDecoder,
+}
+
 #[derive(Deserialize,Serialize)]
 pub struct SourceBLEConfig {
     id : String,
+    #[serde(default)]
+    devices : Vec<BLEDeviceConfig>,
+    #[serde(default)]
+    interval : Option<u64>,
 }
 
 pub struct SourceBLE {
-    #[allow(dead_code)]
     config : Box<SourceBLEConfig>,
     name: String,
+    ble : BleManager,
+    /// Parsed address for each entry in config.devices
+    addresses : Vec<Option<BDAddr>>,
+    /// Latest reading for each object/property since the last poll
+    latest : std::collections::BTreeMap<(String, String), Metric>,
 }
 
 pub fn get_bytes_as_hex(bytes : &[u8]) -> String {
@@ -105,6 +133,13 @@ impl SourceBLEConfig {
     pub fn example_config()->SourceBLEConfig {
         return SourceBLEConfig {
             id: "123".to_string(),
+            devices: vec![BLEDeviceConfig {
+                address: Some("A4:C1:38:00:00:00".to_string()),
+                local_name: None,
+                object: Some("Bathroom".to_string()),
+                decoder: BLEDecoder::ATC,
+            }],
+            interval: None,
         }
     }
 }
@@ -114,28 +149,97 @@ impl SourceConfig for SourceBLEConfig {
     fn name(&self) -> String {
         return format!("bluetooth {}", self.id);
     }
-    fn init(self : Box<Self>) -> Box<dyn Source> {
-        return Box::new( SourceBLE{
-            name: self.name(),
-            config: self
-        } )
+    fn interval(&self) -> Option<u64> {
+        return self.interval;
+    }
+    fn init(self : Box<Self>) -> Result<Box<dyn Source>> {
+        let name = self.name();
+        let mut addresses = vec![];
+        for device in &self.devices {
+            addresses.push(match &device.address {
+                Some(address) => Some(address.parse::<BDAddr>().map_err( |e| Error::Config(format!("bad address {} : {:?}", address, e)) )?),
+                None => None
+            });
+        }
+
+        let mut ble = BleManager::create()?;
+        ble.start_passive_scan()?;
+
+        return Ok(Box::new( SourceBLE{
+            name,
+            config: self,
+            ble,
+            addresses,
+            latest: std::collections::BTreeMap::new()
+        } ))
     }
 }
 
+impl SourceBLE {
+    fn matches(&self, index : usize, address : &BDAddr, local_name : &Option<String>) -> bool {
+        if let Some(wanted) = self.addresses[index] {
+            return wanted == *address;
+        }
+        match (&self.config.devices[index].local_name, local_name) {
+            (Some(wanted), Some(name)) => wanted == name,
+            _ => false
+        }
+    }
+
+    fn handle_advertisement(&mut self, address : &BDAddr, advertisement : &Advertisement, received : DateTime<Utc>) {
+        // Only ask the adapter for the name if something is matching on it
+        let local_name = if self.config.devices.iter().any( |device| device.local_name.is_some() ) {
+            self.ble.adapter.peripheral(*address).and_then( |p| p.properties().local_name )
+        } else {
+            None
+        };
+
+        for index in 0..self.config.devices.len() {
+            if !self.matches(index, address, &local_name) {
+                continue;
+            }
+            let device = &self.config.devices[index];
+            let object = match &device.object {
+                Some(object) => object.clone(),
+                None => address.to_string()
+            };
+            for metric in device.decoder.decode(&object, advertisement) {
+                log::trace!("{} - {} {} = {}", self.name, metric.object, metric.property, metric.value);
+                let key = (metric.object.clone(), metric.property.clone());
+                self.latest.insert(key, metric.with_timestamp(received));
+            }
+        }
+    }
+}
 
 #[async_trait]
 impl Source for SourceBLE {
     fn name(&self) -> &String { 
         return &self.name;
     }
-    async fn poll(&mut self) -> Vec<Metric> {
-        println!("{} - returning value", self.name());
-        return vec![/*Metric {
-            object: self.config.object.clone(),
-            property: self.config.property.clone(),
-            value: format!("{}", self.config.value)
-        }*/]
-        
+    async fn poll(&mut self) -> Result<Vec<Metric>> {
+        let mut advertisements : Vec<_> = self.ble.take_advertisements().into_iter().collect();
+        // Oldest first, so a newer advertisement carrying the same property wins
+        advertisements.sort_by_key( |(_, (received, _))| *received );
+        for ((address, kind), (received, data)) in advertisements {
+            let advertisement = match kind {
+                AdvertisementKind::Manufacturer(id) => Advertisement::Manufacturer { id, data: &data },
+                AdvertisementKind::Service(uuid) => Advertisement::Service { uuid, data: &data },
+            };
+            self.handle_advertisement(&address, &advertisement, received);
+        }
+        let latest = std::mem::take(&mut self.latest);
+        println!("{} - returning {} values", self.name(), latest.len());
+        return Ok(latest.into_iter().map( |(_, metric)| metric ).collect());
+    }
+    fn scan(&mut self) -> Result<bool> {
+        self.ble.stop_scan();
+        self.ble.start_passive_scan()?;
+        return Ok(true);
+    }
+    fn shutdown(&mut self) -> Option<std::thread::JoinHandle<()>> {
+        self.ble.stop_scan();
+        return None;
     }
 }
 
@@ -163,6 +267,16 @@ impl DeviceDB {
 }
 
 
+/// Which advertisement from a device, as some send manufacturer data and service data in turn
+#[derive(Clone,Copy,PartialEq,Eq,Hash,Debug)]
+pub enum AdvertisementKind {
+    Manufacturer(u16),
+    Service(uuid::Uuid),
+}
+
+/// The newest payload of each kind from each device, with when it arrived
+pub type LatestAdvertisements = HashMap<(BDAddr, AdvertisementKind), (DateTime<Utc>, Vec<u8>)>;
+
 pub struct BleManager {
     #[allow(dead_code)]
     manager : Manager,
@@ -174,39 +288,54 @@ pub struct BleManager {
     devices : Arc<Mutex<DeviceDB>>,
     //#[allow(dead_code)]
     poller : Option<std::thread::JoinHandle<()>>,
-    receiver : crossbeam_channel::Receiver<CentralEvent>
+    receiver : crossbeam_channel::Receiver<CentralEvent>,
+    /// Kept up to date by the poller thread whether or not anyone is reading the events
+    advertisements : Arc<Mutex<LatestAdvertisements>>,
 }
 
 impl BleManager {
 
-    pub fn create() -> BleManager {
+    pub fn create() -> Result<BleManager> {
         log::info!("Initialising bluetooth");
 
-        let bluetooth_db = Arc::new(db::BluetoothDB::create());
+        let bluetooth_db = Arc::new(db::BluetoothDB::create()?);
 
         let devices = Arc::new(Mutex::new(DeviceDB { devices: HashMap::new()}));
 
-        let manager = Manager::new().unwrap();
-        let adapter_list : Vec<Adapter> = manager.adapters().unwrap();
+        let manager = Manager::new().map_err( |e| Error::Bluetooth(format!("{:?}", e)) )?;
+        let adapter_list : Vec<Adapter> = manager.adapters().map_err( |e| Error::Bluetooth(format!("{:?}", e)) )?;
         
         log::trace!("Adapters : {}", adapter_list.len() );
 
-        let adapter = adapter_list.into_iter().nth(0).unwrap();
+        let adapter = adapter_list.into_iter().nth(0).ok_or(Error::Bluetooth("no bluetooth adapter found".to_string()))?;
 
         print_adapter_info(&adapter);
 
-        let event_receiver = adapter.event_receiver().unwrap();
+        let event_receiver = adapter.event_receiver().ok_or(Error::Bluetooth("adapter has no event receiver".to_string()))?;
 
         //let db_ref = Arc::clone(&bluetooth_db);
         //let devices_ref = Arc::clone(&devices);
         
         let (ble_sender, ble_receiver) = crossbeam_channel::bounded(100);
+        let advertisements = Arc::new(Mutex::new(HashMap::new()));
+        let advertisements_ref = Arc::clone(&advertisements);
 
         let poller = thread::spawn( move || {
 
             log::info!("Bluetooth Poller started");
             while let Ok(event) = event_receiver.recv() {
-                ble_sender.send(event).unwrap();
+                let advertisement = match &event {
+                    CentralEvent::ManufacturerDataAdvertisement { address, manufacturer_id, data } => Some((*address, AdvertisementKind::Manufacturer(*manufacturer_id), data.clone())),
+                    CentralEvent::ServiceDataAdvertisement { address, service, data } => Some((*address, AdvertisementKind::Service(*service), data.clone())),
+                    _ => None
+                };
+                if let Some((address, kind, data)) = advertisement {
+                    advertisements_ref.lock().unwrap().insert((address, kind), (Utc::now(), data));
+                }
+                // Only the scan and connect commands read the events themselves, so never wait on a full channel
+                if let Err(crossbeam_channel::TrySendError::Disconnected(_)) = ble_sender.try_send(event) {
+                    break;
+                }
             }            
             log::info!("Bluetooth Poller finished");
         });
@@ -214,15 +343,35 @@ impl BleManager {
 
 
         //let adapter = adapter_list.remove(0);
-        return BleManager {
+        return Ok(BleManager {
             manager,
             devices,
             adapter,
             receiver : ble_receiver,
             bluetooth_db : bluetooth_db,
-            poller: Some(poller)
+            poller: Some(poller),
+            advertisements
 
-        };
+        });
+    }
+
+    /// Scan without requesting scan responses, reporting every advertisement rather than just new devices
+    pub fn start_passive_scan(&mut self) -> Result<()> {
+        log::trace!("Starting passive scan");
+        self.adapter.active(false);
+        self.adapter.filter_duplicates(false);
+        return self.adapter.start_scan().map_err( |e| Error::Bluetooth(format!("unable to start scan : {:?}", e)) );
+    }
+
+    pub fn stop_scan(&mut self) {
+        if let Err(e) = self.adapter.stop_scan() {
+            log::warn!("Unable to stop scan {:?}", e);
+        }
+    }
+
+    /// The newest advertisements seen since the last call, without waiting
+    pub fn take_advertisements(&mut self) -> LatestAdvertisements {
+        return std::mem::take(&mut *self.advertisements.lock().unwrap());
     }
 
     pub fn shutdown(&mut self) -> Option<std::thread::JoinHandle<()>> {
@@ -0,0 +1,294 @@
+//! BTHome v2 service data, as broadcast on UUID 0xFCD2.  See https://bthome.io/format/
+
+use super::{Metric, MetricValue};
+
+pub const BTHOME_UUID : u16 = 0xFCD2;
+
+const FLAG_ENCRYPTED : u8 = 0x01;
+const FLAG_TRIGGER_BASED : u8 = 0x04;
+const VERSION_SHIFT : u8 = 5;
+
+const OBJECT_PACKET_ID : u8 = 0x00;
+
+/// How the bytes following an object id are laid out
+#[derive(Clone,Copy)]
+enum Kind {
+    /// Little endian unsigned integer of the given length, multiplied by the factor
+    Unsigned(usize, f64),
+    /// Little endian signed integer of the given length, multiplied by the factor
+    Signed(usize, f64),
+    /// Single byte, 0 or 1
+    Binary,
+    /// Single byte button event
+    Button,
+    /// Event byte then a step count
+    Dimmer,
+    /// Length byte then UTF-8
+    Text,
+    /// Length byte then bytes
+    Raw,
+}
+
+struct ObjectType {
+    id : u8,
+    property : &'static str,
+    kind : Kind,
+    unit : Option<&'static str>,
+}
+
+const fn object(id : u8, property : &'static str, kind : Kind, unit : Option<&'static str>) -> ObjectType {
+    ObjectType { id, property, kind, unit }
+}
+
+const OBJECT_TYPES : &[ObjectType] = &[
+    object(0x01, "Battery", Kind::Unsigned(1, 1.0), Some("%")),
+    object(0x02, "Temperature", Kind::Signed(2, 0.01), Some("°C")),
+    object(0x03, "Humidity", Kind::Unsigned(2, 0.01), Some("%")),
+    object(0x04, "Pressure", Kind::Unsigned(3, 0.01), Some("hPa")),
+    object(0x05, "Illuminance", Kind::Unsigned(3, 0.01), Some("lx")),
+    object(0x06, "Mass", Kind::Unsigned(2, 0.01), Some("kg")),
+    object(0x07, "Mass", Kind::Unsigned(2, 0.01), Some("lb")),
+    object(0x08, "Dewpoint", Kind::Signed(2, 0.01), Some("°C")),
+    object(0x09, "Count", Kind::Unsigned(1, 1.0), None),
+    object(0x0A, "Energy", Kind::Unsigned(3, 0.001), Some("kWh")),
+    object(0x0B, "Power", Kind::Unsigned(3, 0.01), Some("W")),
+    object(0x0C, "Voltage", Kind::Unsigned(2, 0.001), Some("V")),
+    object(0x0D, "PM2.5", Kind::Unsigned(2, 1.0), Some("µg/m³")),
+    object(0x0E, "PM10", Kind::Unsigned(2, 1.0), Some("µg/m³")),
+    object(0x0F, "Generic", Kind::Binary, None),
+    object(0x10, "PowerOn", Kind::Binary, None),
+    object(0x11, "Opening", Kind::Binary, None),
+    object(0x12, "CO2", Kind::Unsigned(2, 1.0), Some("ppm")),
+    object(0x13, "TVOC", Kind::Unsigned(2, 1.0), Some("µg/m³")),
+    object(0x14, "Moisture", Kind::Unsigned(2, 0.01), Some("%")),
+    object(0x15, "BatteryLow", Kind::Binary, None),
+    object(0x16, "BatteryCharging", Kind::Binary, None),
+    object(0x17, "CarbonMonoxide", Kind::Binary, None),
+    object(0x18, "Cold", Kind::Binary, None),
+    object(0x19, "Connectivity", Kind::Binary, None),
+    object(0x1A, "Door", Kind::Binary, None),
+    object(0x1B, "GarageDoor", Kind::Binary, None),
+    object(0x1C, "Gas", Kind::Binary, None),
+    object(0x1D, "Heat", Kind::Binary, None),
+    object(0x1E, "Light", Kind::Binary, None),
+    object(0x1F, "Lock", Kind::Binary, None),
+    object(0x20, "Wet", Kind::Binary, None),
+    object(0x21, "Motion", Kind::Binary, None),
+    object(0x22, "Moving", Kind::Binary, None),
+    object(0x23, "Occupancy", Kind::Binary, None),
+    object(0x24, "Plug", Kind::Binary, None),
+    object(0x25, "Presence", Kind::Binary, None),
+    object(0x26, "Problem", Kind::Binary, None),
+    object(0x27, "Running", Kind::Binary, None),
+    object(0x28, "Safety", Kind::Binary, None),
+    object(0x29, "Smoke", Kind::Binary, None),
+    object(0x2A, "Sound", Kind::Binary, None),
+    object(0x2B, "Tamper", Kind::Binary, None),
+    object(0x2C, "Vibration", Kind::Binary, None),
+    object(0x2D, "Window", Kind::Binary, None),
+    object(0x2E, "Humidity", Kind::Unsigned(1, 1.0), Some("%")),
+    object(0x2F, "Moisture", Kind::Unsigned(1, 1.0), Some("%")),
+    object(0x3A, "Button", Kind::Button, None),
+    object(0x3C, "Dimmer", Kind::Dimmer, None),
+    object(0x3D, "Count", Kind::Unsigned(2, 1.0), None),
+    object(0x3E, "Count", Kind::Unsigned(4, 1.0), None),
+    object(0x3F, "Rotation", Kind::Signed(2, 0.1), Some("°")),
+    object(0x40, "Distance", Kind::Unsigned(2, 1.0), Some("mm")),
+    object(0x41, "Distance", Kind::Unsigned(2, 0.1), Some("m")),
+    object(0x42, "Duration", Kind::Unsigned(3, 0.001), Some("s")),
+    object(0x43, "Current", Kind::Unsigned(2, 0.001), Some("A")),
+    object(0x44, "Speed", Kind::Unsigned(2, 0.01), Some("m/s")),
+    object(0x45, "Temperature", Kind::Signed(2, 0.1), Some("°C")),
+    object(0x46, "UVIndex", Kind::Unsigned(1, 0.1), None),
+    object(0x47, "Volume", Kind::Unsigned(2, 0.1), Some("L")),
+    object(0x48, "Volume", Kind::Unsigned(2, 1.0), Some("mL")),
+    object(0x49, "VolumeFlowRate", Kind::Unsigned(2, 0.001), Some("m³/h")),
+    object(0x4A, "Voltage", Kind::Unsigned(2, 0.1), Some("V")),
+    object(0x4B, "Gas", Kind::Unsigned(3, 0.001), Some("m³")),
+    object(0x4C, "Gas", Kind::Unsigned(4, 0.001), Some("m³")),
+    object(0x4D, "Energy", Kind::Unsigned(4, 0.001), Some("kWh")),
+    object(0x4E, "Volume", Kind::Unsigned(4, 0.001), Some("L")),
+    object(0x4F, "Water", Kind::Unsigned(4, 0.001), Some("L")),
+    object(0x50, "Timestamp", Kind::Unsigned(4, 1.0), Some("s")),
+    object(0x51, "Acceleration", Kind::Unsigned(2, 0.001), Some("m/s²")),
+    object(0x52, "Gyroscope", Kind::Unsigned(2, 0.001), Some("°/s")),
+    object(0x53, "Text", Kind::Text, None),
+    object(0x54, "Raw", Kind::Raw, None),
+    object(0x55, "VolumeStorage", Kind::Unsigned(4, 0.001), Some("L")),
+    object(0xF0, "DeviceType", Kind::Unsigned(2, 1.0), None),
+    object(0xF1, "FirmwareVersion", Kind::Unsigned(4, 1.0), None),
+    object(0xF2, "FirmwareVersion", Kind::Unsigned(3, 1.0), None),
+];
+
+/// A decoded BTHome advertisement
+#[derive(Debug)]
+pub struct BTHomeFrame {
+    pub encrypted : bool,
+    pub trigger_based : bool,
+    pub packet_id : Option<u8>,
+    /// Empty for encrypted frames, since we don't hold any keys
+    pub metrics : Vec<Metric>,
+}
+
+fn read_unsigned(bytes : &[u8]) -> u64 {
+    return bytes.iter().rev().fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+}
+
+fn read_signed(bytes : &[u8]) -> i64 {
+    let unsigned = read_unsigned(bytes);
+    let bits = 8 * bytes.len() as u32;
+    let shift = 64 - bits;
+    return ((unsigned << shift) as i64) >> shift;
+}
+
+fn scaled(raw : i64, factor : f64) -> MetricValue {
+    if factor == 1.0 {
+        return MetricValue::Integer(raw);
+    }
+    // Round away the binary noise from the scaling, e.g. 0.01 * 2312 = 23.120000000000001
+    let decimals = (-factor.log10()).ceil().max(0.0) as i32;
+    let power = 10f64.powi(decimals);
+    return MetricValue::Float(((raw as f64) * factor * power).round() / power);
+}
+
+fn button_event(code : u8) -> String {
+    return match code {
+        0x00 => "none",
+        0x01 => "press",
+        0x02 => "double_press",
+        0x03 => "triple_press",
+        0x04 => "long_press",
+        0x05 => "long_double_press",
+        0x06 => "long_triple_press",
+        0x80 => "hold_press",
+        _ => return format!("unknown_{:02X}", code),
+    }.to_string();
+}
+
+/// Decode a BTHome v2 service data payload into metrics for object.
+/// Repeated measurements of the same kind are numbered, e.g. Temperature, Temperature2.
+pub fn parse_bthome(object : &str, data : &[u8]) -> Result<BTHomeFrame, String> {
+    let (&device_info, mut rest) = data.split_first().ok_or("empty BTHome payload")?;
+    let version = device_info >> VERSION_SHIFT;
+    if version != 2 {
+        return Err(format!("unsupported BTHome version {}", version));
+    }
+
+    let mut frame = BTHomeFrame {
+        encrypted: device_info & FLAG_ENCRYPTED != 0,
+        trigger_based: device_info & FLAG_TRIGGER_BASED != 0,
+        packet_id: None,
+        metrics: vec![],
+    };
+    if frame.encrypted {
+        return Ok(frame);
+    }
+
+    let mut seen : std::collections::HashMap<&str, usize> = std::collections::HashMap::new();
+    while let Some((&id, after_id)) = rest.split_first() {
+        if id == OBJECT_PACKET_ID {
+            let (&packet_id, after) = after_id.split_first().ok_or("truncated packet id")?;
+            frame.packet_id = Some(packet_id);
+            rest = after;
+            continue;
+        }
+
+        let object_type = OBJECT_TYPES.iter().find( |t| t.id == id ).ok_or(format!("unknown BTHome object id {:#04x}", id))?;
+
+        let (length, data_start) = match object_type.kind {
+            Kind::Unsigned(length, _) | Kind::Signed(length, _) => (length, 0),
+            Kind::Binary | Kind::Button => (1, 0),
+            Kind::Dimmer => (2, 0),
+            Kind::Text | Kind::Raw => (*after_id.first().ok_or("truncated length")? as usize, 1),
+        };
+        if after_id.len() < data_start + length {
+            return Err(format!("truncated BTHome object {:#04x}", id));
+        }
+        let bytes = &after_id[data_start..data_start + length];
+        rest = &after_id[data_start + length..];
+
+        let value = match object_type.kind {
+            Kind::Unsigned(_, factor) => scaled(read_unsigned(bytes) as i64, factor),
+            Kind::Signed(_, factor) => scaled(read_signed(bytes), factor),
+            Kind::Binary => MetricValue::Boolean(bytes[0] != 0),
+            Kind::Button => MetricValue::Text(button_event(bytes[0])),
+            // Rotate left counts down, rotate right counts up
+            Kind::Dimmer => MetricValue::Integer(match bytes[0] { 0x01 => -(bytes[1] as i64), 0x02 => bytes[1] as i64, _ => 0 }),
+            Kind::Text => MetricValue::Text(String::from_utf8_lossy(bytes).to_string()),
+            Kind::Raw => MetricValue::Text(super::get_bytes_as_hex(bytes)),
+        };
+
+        let count = seen.entry(object_type.property).or_insert(0);
+        *count += 1;
+        let property = if *count == 1 { object_type.property.to_string() } else { format!("{}{}", object_type.property, count) };
+
+        let mut metric = Metric::new(object, &property, value);
+        if let Some(unit) = object_type.unit {
+            metric = metric.with_unit(unit);
+        }
+        frame.metrics.push(metric);
+    }
+    return Ok(frame);
+}
+
+
+#[cfg(test)]
+fn values(frame : &BTHomeFrame) -> Vec<(String, String, Option<String>)> {
+    return frame.metrics.iter().map( |m| (m.property.clone(), m.value.to_string(), m.unit.clone()) ).collect();
+}
+
+#[test]
+fn test_bthome_temperature_humidity() {
+    // Example from bthome.io: packet id 9, temperature 23.12C, humidity 56.78%
+    let frame = parse_bthome("Sensor", &[0x40, 0x00, 0x09, 0x02, 0x08, 0x09, 0x03, 0x2E, 0x16]).unwrap();
+    assert!(!frame.encrypted);
+    assert!(!frame.trigger_based);
+    assert_eq!(frame.packet_id, Some(9));
+    assert_eq!(values(&frame), vec![
+        ("Temperature".to_string(), "23.12".to_string(), Some("°C".to_string())),
+        ("Humidity".to_string(), "56.78".to_string(), Some("%".to_string())),
+    ]);
+}
+
+#[test]
+fn test_bthome_mixed_objects() {
+    // Battery 97%, negative temperature, pressure 1008.83hPa, motion, window open, button double press
+    let data = [0x44, 0x01, 0x61, 0x02, 0xCA, 0xFD, 0x04, 0x13, 0x8A, 0x01, 0x21, 0x01, 0x2D, 0x01, 0x3A, 0x02];
+    let frame = parse_bthome("Sensor", &data).unwrap();
+    assert!(frame.trigger_based);
+    assert_eq!(values(&frame), vec![
+        ("Battery".to_string(), "97".to_string(), Some("%".to_string())),
+        ("Temperature".to_string(), "-5.66".to_string(), Some("°C".to_string())),
+        ("Pressure".to_string(), "1008.83".to_string(), Some("hPa".to_string())),
+        ("Motion".to_string(), "true".to_string(), None),
+        ("Window".to_string(), "true".to_string(), None),
+        ("Button".to_string(), "double_press".to_string(), None),
+    ]);
+}
+
+#[test]
+fn test_bthome_repeated_objects() {
+    let frame = parse_bthome("Sensor", &[0x40, 0x45, 0x11, 0x01, 0x45, 0xFF, 0xFF]).unwrap();
+    assert_eq!(values(&frame), vec![
+        ("Temperature".to_string(), "27.3".to_string(), Some("°C".to_string())),
+        ("Temperature2".to_string(), "-0.1".to_string(), Some("°C".to_string())),
+    ]);
+}
+
+#[test]
+fn test_bthome_encrypted() {
+    let frame = parse_bthome("Sensor", &[0x41, 0xA4, 0x72, 0x66, 0xC9, 0x5F, 0x73, 0x00, 0x11, 0x22, 0x33, 0x78, 0x23, 0x72, 0x14]).unwrap();
+    assert!(frame.encrypted);
+    assert!(frame.metrics.is_empty());
+}
+
+#[test]
+fn test_bthome_errors() {
+    assert!(parse_bthome("Sensor", &[]).is_err());
+    // Version 1 header
+    assert!(parse_bthome("Sensor", &[0x20, 0x02, 0x08, 0x09]).is_err());
+    // Truncated temperature
+    assert!(parse_bthome("Sensor", &[0x40, 0x02, 0x08]).is_err());
+    // Unknown object id
+    assert!(parse_bthome("Sensor", &[0x40, 0xEE, 0x01]).is_err());
+}
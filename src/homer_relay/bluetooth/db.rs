@@ -1,6 +1,8 @@
 use serde::Deserialize;
 use uuid::Uuid;
 
+use super::{Error, Result};
+
 #[derive(Deserialize)]
 struct CompanyJSON {
     code: u16,
@@ -33,43 +35,59 @@ const BTLT_UUID_D2 : u16 = 0;
 const BTLT_UUID_D3 : u16 = 0x1000;
 const BTLT_UUID_D4 : [u8;8]= [0x80,0x00,0x00,0x80,0x5F,0x9B,0x34,0xFB];
 
+/// Expand a 16 bit assigned number into a full Bluetooth base UUID
+pub fn uuid_from_short(short : u16) -> Uuid {
+    return Uuid::from_fields( short as u32,BTLT_UUID_D2,BTLT_UUID_D3,&BTLT_UUID_D4).unwrap();
+}
+
+/// The 16 bit assigned number for a Bluetooth base UUID, if it is one
+pub fn uuid_to_short(uuid : &Uuid) -> Option<u16> {
+    match uuid.as_fields() {
+        (a,BTLT_UUID_D2,BTLT_UUID_D3,&BTLT_UUID_D4) if a <= 0xFFFF => Some(a as u16),
+        _ => None
+    }
+}
+
 impl BluetoothDB {
 
-    fn read_name_code_file(filename : &str)-> std::collections::HashMap<u16, String> {
+    fn open(filename : &str) -> Result<std::fs::File> {
         log::trace!("Parsing {}", filename);
-        let file = std::fs::File::open(filename).unwrap();
-        let json : Vec<CompanyJSON> = serde_json::from_reader(file).unwrap();
-        return json.into_iter().map( |x| (x.code, x.name)).collect();
+        return std::fs::File::open(filename).map_err( |e| Error::Config(format!("unable to open {} : {}", filename, e)) );
+    }
+
+    fn read_name_code_file(filename : &str)-> Result<std::collections::HashMap<u16, String>> {
+        let json : Vec<CompanyJSON> = serde_json::from_reader(BluetoothDB::open(filename)?)?;
+        return Ok(json.into_iter().map( |x| (x.code, x.name)).collect());
     }
 
-    fn parse_uuid( string : &str) -> Uuid {
+    fn parse_uuid( string : &str) -> Result<Uuid> {
+        let bad_uuid = |e| Error::Parse(format!("bad uuid {} : {:?}", string, e));
         if string.len() == 4 {
-            let d1 = u32::from_str_radix(string, 16).unwrap();
-            return Uuid::from_fields( d1,BTLT_UUID_D2,BTLT_UUID_D3,&BTLT_UUID_D4).unwrap();
+            let d1 = u32::from_str_radix(string, 16).map_err( |e| bad_uuid(format!("{:?}", e)) )?;
+            return Uuid::from_fields( d1,BTLT_UUID_D2,BTLT_UUID_D3,&BTLT_UUID_D4).map_err( |e| bad_uuid(format!("{:?}", e)) );
         } else if string.len() == 36  {
-            return Uuid::parse_str( string ).unwrap();
+            return Uuid::parse_str( string ).map_err( |e| bad_uuid(format!("{:?}", e)) );
         } else {
-            panic!("Unexpected length of uuid {} ({})" , string, string.len())
+            return Err(Error::Parse(format!("Unexpected length of uuid {} ({})" , string, string.len())));
         }
     }
 
-    fn read_descriptor_file(filename : &str) -> std::collections::HashMap<Uuid, BluetoothMetadata> {
-        log::trace!("Parsing {}", filename);
-        let file = std::fs::File::open(filename).unwrap();
-        let json : Vec<BluetoothMetadata> = serde_json::from_reader(file).unwrap();
-        let ret : std::collections::HashMap<Uuid, BluetoothMetadata> = json.into_iter().map( |x| 
-            (BluetoothDB::parse_uuid(&x.uuid), x ) 
-        ).collect();
-        return ret;
+    fn read_descriptor_file(filename : &str) -> Result<std::collections::HashMap<Uuid, BluetoothMetadata>> {
+        let json : Vec<BluetoothMetadata> = serde_json::from_reader(BluetoothDB::open(filename)?)?;
+        let mut ret : std::collections::HashMap<Uuid, BluetoothMetadata> = std::collections::HashMap::new();
+        for x in json {
+            ret.insert(BluetoothDB::parse_uuid(&x.uuid)?, x);
+        }
+        return Ok(ret);
     }
 
-    pub fn create() -> BluetoothDB {
-        return BluetoothDB {
-            map_company : BluetoothDB::read_name_code_file("data/bluetooth-numbers-database/v1/company_ids.json"),
-            map_characteristic : BluetoothDB::read_descriptor_file("data/bluetooth-numbers-database/v1/characteristic_uuids.json"),
-            map_service : BluetoothDB::read_descriptor_file("data/bluetooth-numbers-database/v1/service_uuids.json"),
-            map_descriptor : BluetoothDB::read_descriptor_file("data/bluetooth-numbers-database/v1/descriptor_uuids.json"),
-        }
+    pub fn create() -> Result<BluetoothDB> {
+        return Ok(BluetoothDB {
+            map_company : BluetoothDB::read_name_code_file("data/bluetooth-numbers-database/v1/company_ids.json")?,
+            map_characteristic : BluetoothDB::read_descriptor_file("data/bluetooth-numbers-database/v1/characteristic_uuids.json")?,
+            map_service : BluetoothDB::read_descriptor_file("data/bluetooth-numbers-database/v1/service_uuids.json")?,
+            map_descriptor : BluetoothDB::read_descriptor_file("data/bluetooth-numbers-database/v1/descriptor_uuids.json")?,
+        })
     }
 
     pub fn get_company(&self, id : u16) -> String {
@@ -106,13 +124,21 @@ impl BluetoothDB {
 
 
 #[test]
-#[should_panic]
 fn parse_error() {
-    BluetoothDB::parse_uuid("jibberish");
+    assert!(BluetoothDB::parse_uuid("jibberish").is_err());
+    assert!(BluetoothDB::parse_uuid("zzzz").is_err());
 }      
 
+#[test]
+fn test_short_uuid_round_trip() {
+    let uuid = uuid_from_short(0xFCD2);
+    assert_eq!(uuid, Uuid::parse_str("0000fcd2-0000-1000-8000-00805f9b34fb").unwrap());
+    assert_eq!(uuid_to_short(&uuid), Some(0xFCD2));
+    assert_eq!(uuid_to_short(&Uuid::parse_str("6e400001-b5a3-f393-e0a9-e50e24dcca9e").unwrap()), None);
+}
+
 #[test]
 fn test_parse_len_4() {
-    let uuid = BluetoothDB::parse_uuid("1234");
+    let uuid = BluetoothDB::parse_uuid("1234").unwrap();
     assert_eq!(uuid, Uuid::parse_str("00001234-0000-1000-8000-00805f9b34fb").unwrap());
 }
\ No newline at end of file
@@ -0,0 +1,161 @@
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use super::Metric;
+use super::get_bytes_as_hex;
+use super::db;
+use super::bthome;
+use super::xiaomi;
+use super::ruuvi;
+
+/// The payload part of an advertisement, as pulled out of a CentralEvent
+#[derive(Debug)]
+pub enum Advertisement<'a> {
+    Manufacturer { id : u16, data : &'a [u8] },
+    Service { uuid : Uuid, data : &'a [u8] },
+}
+
+/// How to turn advertisements from a device into metrics
+#[derive(Deserialize,Serialize,Clone,Copy,PartialEq,Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum BLEDecoder {
+    /// Publish the payload bytes as hex, handy for working out what a new sensor sends
+    Raw,
+    /// BTHome v2 service data, see https://bthome.io
+    BTHome,
+    /// Xiaomi LYWSD03MMC with ATC or pvvx firmware, on the 0x181A service
+    #[serde(alias = "pvvx")]
+    ATC,
+    /// RuuviTag data format 5 manufacturer data
+    Ruuvi,
+}
+
+impl Default for BLEDecoder {
+    fn default() -> BLEDecoder {
+        return BLEDecoder::Raw;
+    }
+}
+
+impl BLEDecoder {
+    /// Decode an advertisement into metrics for object, or nothing if it isn't one this decoder understands
+    pub fn decode(&self, object : &str, advertisement : &Advertisement) -> Vec<Metric> {
+        match self {
+            BLEDecoder::Raw => decode_raw(object, advertisement),
+            BLEDecoder::BTHome => decode_bthome(object, advertisement),
+            BLEDecoder::ATC => decode_atc(object, advertisement),
+            BLEDecoder::Ruuvi => decode_ruuvi(object, advertisement),
+        }
+    }
+}
+
+/// Round a scaled reading, so 0.005 * 4860 comes out as 24.3 rather than 24.300000000000001
+pub fn round(value : f64, decimals : i32) -> f64 {
+    let power = 10f64.powi(decimals);
+    return (value * power).round() / power;
+}
+
+fn service_data<'a>(advertisement : &Advertisement<'a>, short_uuid : u16) -> Option<&'a [u8]> {
+    match advertisement {
+        Advertisement::Service { uuid, data } if db::uuid_to_short(uuid) == Some(short_uuid) => Some(data),
+        _ => None
+    }
+}
+
+fn decode_bthome(object : &str, advertisement : &Advertisement) -> Vec<Metric> {
+    let data = match service_data(advertisement, bthome::BTHOME_UUID) {
+        Some(data) => data,
+        None => return vec![]
+    };
+    match bthome::parse_bthome(object, data) {
+        Ok(frame) => {
+            log::trace!("{} - BTHome packet {:?}, trigger based {}", object, frame.packet_id, frame.trigger_based);
+            if frame.encrypted {
+                log::debug!("{} - ignoring encrypted BTHome frame", object);
+            }
+            frame.metrics
+        }
+        Err(e) => {
+            log::debug!("{} - unable to decode BTHome frame {} : {}", object, get_bytes_as_hex(data), e);
+            vec![]
+        }
+    }
+}
+
+fn decode_atc(object : &str, advertisement : &Advertisement) -> Vec<Metric> {
+    let data = match service_data(advertisement, xiaomi::ENVIRONMENTAL_SENSING_UUID) {
+        Some(data) => data,
+        None => return vec![]
+    };
+    match xiaomi::parse_atc(object, data) {
+        Ok(metrics) => metrics,
+        Err(e) => {
+            log::debug!("{} - unable to decode ATC frame {} : {}", object, get_bytes_as_hex(data), e);
+            vec![]
+        }
+    }
+}
+
+fn decode_ruuvi(object : &str, advertisement : &Advertisement) -> Vec<Metric> {
+    let data = match advertisement {
+        Advertisement::Manufacturer { id: ruuvi::RUUVI_COMPANY_ID, data } => data,
+        _ => return vec![]
+    };
+    match ruuvi::parse_rawv2(object, data) {
+        Ok(metrics) => metrics,
+        Err(e) => {
+            log::debug!("{} - unable to decode Ruuvi frame {} : {}", object, get_bytes_as_hex(data), e);
+            vec![]
+        }
+    }
+}
+
+fn decode_raw(object : &str, advertisement : &Advertisement) -> Vec<Metric> {
+    match advertisement {
+        Advertisement::Manufacturer { id, data } => {
+            vec![Metric::new(object, &format!("ManufacturerData{:04X}", id), get_bytes_as_hex(data))]
+        }
+        Advertisement::Service { uuid, data } => {
+            let property = match db::uuid_to_short(uuid) {
+                Some(short) => format!("ServiceData{:04X}", short),
+                None => format!("ServiceData{}", uuid),
+            };
+            vec![Metric::new(object, &property, get_bytes_as_hex(data))]
+        }
+    }
+}
+
+
+#[test]
+fn test_decode_raw() {
+    let data = [0x40, 0x02, 0xC4, 0x09];
+    let metrics = BLEDecoder::Raw.decode("Sensor", &Advertisement::Service { uuid: db::uuid_from_short(0xFCD2), data: &data });
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(metrics[0].property, "ServiceDataFCD2");
+    assert_eq!(metrics[0].value.to_string(), "40:02:C4:09");
+
+    let metrics = BLEDecoder::Raw.decode("Sensor", &Advertisement::Manufacturer { id: 0x0499, data: &data[..2] });
+    assert_eq!(metrics[0].property, "ManufacturerData0499");
+    assert_eq!(metrics[0].value.to_string(), "40:02");
+}
+
+#[test]
+fn test_decode_bthome_only_uses_its_service() {
+    let data = [0x40, 0x02, 0x08, 0x09];
+    let metrics = BLEDecoder::BTHome.decode("Sensor", &Advertisement::Service { uuid: db::uuid_from_short(bthome::BTHOME_UUID), data: &data });
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(metrics[0].property, "Temperature");
+    assert!(BLEDecoder::BTHome.decode("Sensor", &Advertisement::Service { uuid: db::uuid_from_short(0x181A), data: &data }).is_empty());
+    assert!(BLEDecoder::BTHome.decode("Sensor", &Advertisement::Manufacturer { id: 0x0499, data: &data }).is_empty());
+}
+
+#[test]
+fn test_decoder_names() {
+    #[derive(Deserialize)]
+    struct Device { decoder : BLEDecoder }
+    let parse = |name : &str| toml::from_str::<Device>(&format!("decoder = \"{}\"", name)).unwrap().decoder;
+    assert_eq!(parse("raw"), BLEDecoder::Raw);
+    assert_eq!(parse("bthome"), BLEDecoder::BTHome);
+    assert_eq!(parse("atc"), BLEDecoder::ATC);
+    assert_eq!(parse("pvvx"), BLEDecoder::ATC);
+    assert_eq!(parse("ruuvi"), BLEDecoder::Ruuvi);
+}
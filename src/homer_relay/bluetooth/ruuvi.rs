@@ -0,0 +1,153 @@
+//! RuuviTag RAWv2 (data format 5) manufacturer data, see https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-5-rawv2
+
+use super::Metric;
+use super::decoder::round;
+
+/// Ruuvi Innovations Ltd.
+pub const RUUVI_COMPANY_ID : u16 = 0x0499;
+
+const FORMAT_RAWV2 : u8 = 5;
+const RAWV2_LENGTH : usize = 24;
+
+const SIGNED_NOT_AVAILABLE : i16 = i16::MIN;
+const UNSIGNED_NOT_AVAILABLE : u16 = u16::MAX;
+const BATTERY_NOT_AVAILABLE : u16 = 0x7FF;
+const TX_POWER_NOT_AVAILABLE : u16 = 0x1F;
+const MOVEMENT_NOT_AVAILABLE : u8 = 0xFF;
+
+fn signed(data : &[u8], offset : usize) -> Option<i16> {
+    let value = i16::from_be_bytes([data[offset], data[offset+1]]);
+    return if value == SIGNED_NOT_AVAILABLE { None } else { Some(value) };
+}
+
+fn unsigned(data : &[u8], offset : usize) -> Option<u16> {
+    let value = u16::from_be_bytes([data[offset], data[offset+1]]);
+    return if value == UNSIGNED_NOT_AVAILABLE { None } else { Some(value) };
+}
+
+/// Decode the manufacturer data (without the company id) into metrics for object.
+/// Readings the tag marks as not available are left out rather than published.
+pub fn parse_rawv2(object : &str, data : &[u8]) -> Result<Vec<Metric>, String> {
+    match data.first() {
+        Some(&FORMAT_RAWV2) => (),
+        Some(format) => return Err(format!("unsupported Ruuvi data format {}", format)),
+        None => return Err("empty Ruuvi payload".to_string()),
+    }
+    if data.len() < RAWV2_LENGTH {
+        return Err(format!("Ruuvi RAWv2 payload too short ({} bytes)", data.len()));
+    }
+
+    let mut metrics = vec![];
+    if let Some(temperature) = signed(data, 1) {
+        metrics.push(Metric::new(object, "Temperature", round(temperature as f64 * 0.005, 3)).with_unit("°C"));
+    }
+    if let Some(humidity) = unsigned(data, 3) {
+        metrics.push(Metric::new(object, "Humidity", round(humidity as f64 * 0.0025, 4)).with_unit("%"));
+    }
+    if let Some(pressure) = unsigned(data, 5) {
+        metrics.push(Metric::new(object, "Pressure", round((pressure as f64 + 50000.0) / 100.0, 2)).with_unit("hPa"));
+    }
+    for (property, offset) in &[("AccelerationX", 7), ("AccelerationY", 9), ("AccelerationZ", 11)] {
+        if let Some(milli_g) = signed(data, *offset) {
+            metrics.push(Metric::new(object, property, round(milli_g as f64 / 1000.0, 3)).with_unit("g"));
+        }
+    }
+
+    // 11 bits of battery voltage above 1.6V, then 5 bits of TX power above -40dBm in 2dBm steps
+    let power_info = u16::from_be_bytes([data[13], data[14]]);
+    let battery = power_info >> 5;
+    if battery != BATTERY_NOT_AVAILABLE {
+        metrics.push(Metric::new(object, "BatteryVoltage", round((battery as f64 + 1600.0) / 1000.0, 3)).with_unit("V"));
+    }
+    let tx_power = power_info & 0x1F;
+    if tx_power != TX_POWER_NOT_AVAILABLE {
+        metrics.push(Metric::new(object, "TxPower", -40 + 2 * tx_power as i64).with_unit("dBm"));
+    }
+
+    if data[15] != MOVEMENT_NOT_AVAILABLE {
+        metrics.push(Metric::new(object, "MovementCounter", data[15] as i64));
+    }
+    if let Some(sequence) = unsigned(data, 16) {
+        metrics.push(Metric::new(object, "SequenceNumber", sequence as i64));
+    }
+    return Ok(metrics);
+}
+
+
+#[cfg(test)]
+fn decode_hex(hex : &str) -> Vec<u8> {
+    return (0..hex.len()).step_by(2).map( |i| u8::from_str_radix(&hex[i..i+2], 16).unwrap() ).collect();
+}
+
+#[cfg(test)]
+fn values(metrics : &Vec<Metric>) -> Vec<(String, String)> {
+    return metrics.iter().map( |m| (m.property.clone(), m.value.to_string()) ).collect();
+}
+
+#[test]
+fn test_rawv2_valid() {
+    // Test vectors from the Ruuvi documentation
+    let metrics = parse_rawv2("Shed", &decode_hex("0512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F")).unwrap();
+    assert_eq!(values(&metrics), vec![
+        ("Temperature".to_string(), "24.3".to_string()),
+        ("Humidity".to_string(), "53.49".to_string()),
+        ("Pressure".to_string(), "1000.44".to_string()),
+        ("AccelerationX".to_string(), "0.004".to_string()),
+        ("AccelerationY".to_string(), "-0.004".to_string()),
+        ("AccelerationZ".to_string(), "1.036".to_string()),
+        ("BatteryVoltage".to_string(), "2.977".to_string()),
+        ("TxPower".to_string(), "4".to_string()),
+        ("MovementCounter".to_string(), "66".to_string()),
+        ("SequenceNumber".to_string(), "205".to_string()),
+    ]);
+    assert_eq!(metrics[0].unit, Some("°C".to_string()));
+}
+
+#[test]
+fn test_rawv2_limits() {
+    let maximum = parse_rawv2("Shed", &decode_hex("057FFFFFFEFFFE7FFF7FFF7FFFFFDEFEFFFECBB8334C884F")).unwrap();
+    assert_eq!(values(&maximum), vec![
+        ("Temperature".to_string(), "163.835".to_string()),
+        ("Humidity".to_string(), "163.835".to_string()),
+        ("Pressure".to_string(), "1155.34".to_string()),
+        ("AccelerationX".to_string(), "32.767".to_string()),
+        ("AccelerationY".to_string(), "32.767".to_string()),
+        ("AccelerationZ".to_string(), "32.767".to_string()),
+        ("BatteryVoltage".to_string(), "3.646".to_string()),
+        ("TxPower".to_string(), "20".to_string()),
+        ("MovementCounter".to_string(), "254".to_string()),
+        ("SequenceNumber".to_string(), "65534".to_string()),
+    ]);
+
+    let minimum = parse_rawv2("Shed", &decode_hex("058001000000008001800180010000000000CBB8334C884F")).unwrap();
+    assert_eq!(values(&minimum), vec![
+        ("Temperature".to_string(), "-163.835".to_string()),
+        ("Humidity".to_string(), "0".to_string()),
+        ("Pressure".to_string(), "500".to_string()),
+        ("AccelerationX".to_string(), "-32.767".to_string()),
+        ("AccelerationY".to_string(), "-32.767".to_string()),
+        ("AccelerationZ".to_string(), "-32.767".to_string()),
+        ("BatteryVoltage".to_string(), "1.6".to_string()),
+        ("TxPower".to_string(), "-40".to_string()),
+        ("MovementCounter".to_string(), "0".to_string()),
+        ("SequenceNumber".to_string(), "0".to_string()),
+    ]);
+}
+
+#[test]
+fn test_rawv2_not_available() {
+    let metrics = parse_rawv2("Shed", &decode_hex("058000FFFFFFFF800080008000FFFFFFFFFFFFFFFFFFFFFF")).unwrap();
+    assert!(metrics.is_empty());
+
+    // Only humidity missing, as on a RuuviTag Pro without the humidity sensor
+    let metrics = parse_rawv2("Shed", &decode_hex("0512FCFFFFC37C0004FFFC040CAC364200CDCBB8334C884F")).unwrap();
+    assert!(metrics.iter().all( |m| m.property != "Humidity" ));
+    assert_eq!(metrics.len(), 9);
+}
+
+#[test]
+fn test_rawv2_errors() {
+    assert!(parse_rawv2("Shed", &[]).is_err());
+    assert!(parse_rawv2("Shed", &decode_hex("0301")).is_err());
+    assert!(parse_rawv2("Shed", &decode_hex("0512FC5394")).is_err());
+}
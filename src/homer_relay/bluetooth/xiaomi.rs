@@ -0,0 +1,85 @@
+//! Xiaomi LYWSD03MMC thermometers running the ATC or pvvx custom firmware.
+//! Both broadcast service data on the Environmental Sensing UUID, see https://github.com/pvvx/ATC_MiThermometer
+
+use super::Metric;
+use super::decoder::round;
+
+pub const ENVIRONMENTAL_SENSING_UUID : u16 = 0x181A;
+
+/// MAC[6] big endian, temperature i16 BE (0.1C), humidity u8, battery u8 %, battery u16 BE mV, frame counter u8
+const ATC_LENGTH : usize = 13;
+/// MAC[6] little endian, temperature i16 LE (0.01C), humidity u16 LE (0.01%), battery u16 LE mV, battery u8 %, counter u8, flags u8
+const PVVX_LENGTH : usize = 15;
+/// The pvvx layout with a two byte trailer on newer firmware
+const PVVX_LONG_LENGTH : usize = 17;
+
+/// Decode 0x181A service data from either firmware, telling them apart by length
+pub fn parse_atc(object : &str, data : &[u8]) -> Result<Vec<Metric>, String> {
+    let (temperature, humidity, battery_percent, battery_mv, counter) = match data.len() {
+        ATC_LENGTH => (
+            i16::from_be_bytes([data[6], data[7]]) as f64 / 10.0,
+            data[8] as f64,
+            data[9],
+            u16::from_be_bytes([data[10], data[11]]),
+            data[12],
+        ),
+        PVVX_LENGTH | PVVX_LONG_LENGTH => (
+            round(i16::from_le_bytes([data[6], data[7]]) as f64 / 100.0, 2),
+            round(u16::from_le_bytes([data[8], data[9]]) as f64 / 100.0, 2),
+            data[12],
+            u16::from_le_bytes([data[10], data[11]]),
+            data[13],
+        ),
+        length => return Err(format!("unexpected ATC/pvvx payload length {}", length)),
+    };
+
+    return Ok(vec![
+        Metric::new(object, "Temperature", temperature).with_unit("°C"),
+        Metric::new(object, "Humidity", humidity).with_unit("%"),
+        Metric::new(object, "Battery", battery_percent as i64).with_unit("%"),
+        Metric::new(object, "BatteryVoltage", battery_mv as i64).with_unit("mV"),
+        Metric::new(object, "FrameCounter", counter as i64),
+    ]);
+}
+
+
+#[cfg(test)]
+fn values(metrics : &Vec<Metric>) -> Vec<(String, String)> {
+    return metrics.iter().map( |m| (m.property.clone(), m.value.to_string()) ).collect();
+}
+
+#[test]
+fn test_parse_atc_layout() {
+    // A4:C1:38:12:34:56, 22.7C, 48%, 87%, 2944mV, frame 0x21
+    let data = [0xA4, 0xC1, 0x38, 0x12, 0x34, 0x56, 0x00, 0xE3, 0x30, 0x57, 0x0B, 0x80, 0x21];
+    assert_eq!(values(&parse_atc("Bathroom", &data).unwrap()), vec![
+        ("Temperature".to_string(), "22.7".to_string()),
+        ("Humidity".to_string(), "48".to_string()),
+        ("Battery".to_string(), "87".to_string()),
+        ("BatteryVoltage".to_string(), "2944".to_string()),
+        ("FrameCounter".to_string(), "33".to_string()),
+    ]);
+}
+
+#[test]
+fn test_parse_pvvx_layout() {
+    // MAC reversed, -1.5C, 65.43%, 3012mV, 95%, counter 7, flags 4
+    let data = [0x56, 0x34, 0x12, 0x38, 0xC1, 0xA4, 0x6A, 0xFF, 0x8F, 0x19, 0xC4, 0x0B, 0x5F, 0x07, 0x04];
+    let expected = vec![
+        ("Temperature".to_string(), "-1.5".to_string()),
+        ("Humidity".to_string(), "65.43".to_string()),
+        ("Battery".to_string(), "95".to_string()),
+        ("BatteryVoltage".to_string(), "3012".to_string()),
+        ("FrameCounter".to_string(), "7".to_string()),
+    ];
+    assert_eq!(values(&parse_atc("Bathroom", &data).unwrap()), expected);
+
+    let mut long = data.to_vec();
+    long.extend_from_slice(&[0x00, 0x00]);
+    assert_eq!(values(&parse_atc("Bathroom", &long).unwrap()), expected);
+}
+
+#[test]
+fn test_parse_atc_bad_length() {
+    assert!(parse_atc("Bathroom", &[0xA4, 0xC1, 0x38]).is_err());
+}
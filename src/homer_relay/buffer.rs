@@ -0,0 +1,363 @@
+//! Store and forward for destinations that go offline.  Batches that fail to send are appended
+//! to a JSON Lines file and replayed in order, with backoff, once the destination is back.
+
+use super::core::*;
+
+use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Utc};
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::time::{Duration, Instant};
+
+fn default_max_bytes() -> u64 {
+    return 10 * 1024 * 1024;
+}
+
+fn default_max_age() -> u64 {
+    return 7 * 24 * 60 * 60;
+}
+
+fn default_retry_initial() -> u64 {
+    return 5;
+}
+
+fn default_retry_max() -> u64 {
+    return 300;
+}
+
+#[derive(Deserialize,Serialize,Clone)]
+pub struct BufferConfig {
+    /// File the queue is kept in, one per destination
+    pub path : String,
+    /// Oldest batches are dropped once the queue is bigger than this
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes : u64,
+    /// Seconds before a queued batch is dropped
+    #[serde(default = "default_max_age")]
+    pub max_age : u64,
+    /// Seconds to wait before the first retry, doubling on each failure up to retry_max
+    #[serde(default = "default_retry_initial")]
+    pub retry_initial : u64,
+    #[serde(default = "default_retry_max")]
+    pub retry_max : u64,
+}
+
+impl BufferConfig {
+    pub fn example_config(path : &str) -> BufferConfig {
+        return BufferConfig {
+            path: path.to_string(),
+            max_bytes: default_max_bytes(),
+            max_age: default_max_age(),
+            retry_initial: default_retry_initial(),
+            retry_max: default_retry_max(),
+        }
+    }
+}
+
+#[derive(Deserialize,Serialize)]
+struct Batch {
+    queued : DateTime<Utc>,
+    metrics : Vec<Metric>,
+}
+
+struct QueuedBatch {
+    batch : Batch,
+    /// Size of the line in the file
+    bytes : u64,
+}
+
+pub struct DiskBuffer {
+    config : BufferConfig,
+    name : String,
+    queue : VecDeque<QueuedBatch>,
+    bytes : u64,
+    /// The file holds batches we've already sent and needs rewriting
+    dirty : bool,
+    failures : u32,
+    next_attempt : Instant,
+    pub dropped_batches : u64,
+    pub dropped_metrics : u64,
+}
+
+impl DiskBuffer {
+    /// Open the queue for a destination, picking up anything left from a previous run
+    pub fn open(name : &str, config : BufferConfig) -> DiskBuffer {
+        let mut buffer = DiskBuffer {
+            config,
+            name: name.to_string(),
+            queue: VecDeque::new(),
+            bytes: 0,
+            dirty: false,
+            failures: 0,
+            next_attempt: Instant::now(),
+            dropped_batches: 0,
+            dropped_metrics: 0,
+        };
+
+        if let Ok(file) = std::fs::File::open(&buffer.config.path) {
+            for line in std::io::BufReader::new(file).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break
+                };
+                match serde_json::from_str::<Batch>(&line) {
+                    Ok(batch) => {
+                        let bytes = line.len() as u64 + 1;
+                        buffer.bytes += bytes;
+                        buffer.queue.push_back(QueuedBatch { batch, bytes });
+                    }
+                    // Most likely a half written line from a crash, the rest is still good
+                    Err(e) => log::warn!("{} - skipping unreadable line in {} : {:?}", buffer.name, buffer.config.path, e)
+                }
+            }
+            buffer.expire();
+            println!("{} - {} batches waiting in {}", buffer.name, buffer.queue.len(), buffer.config.path);
+        }
+        return buffer;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.queue.is_empty();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.queue.len();
+    }
+
+    /// Queue a batch behind anything already waiting
+    pub fn push(&mut self, metrics : &Vec<Metric>) {
+        let batch = Batch { queued: Utc::now(), metrics: metrics.clone() };
+        let line = serde_json::to_string(&batch).unwrap();
+        let bytes = line.len() as u64 + 1;
+
+        let appended = std::fs::OpenOptions::new().create(true).append(true).open(&self.config.path)
+            .and_then( |mut file| writeln!(file, "{}", line) );
+        if let Err(e) = appended {
+            log::error!("{} - unable to write to {}, keeping batch in memory : {:?}", self.name, self.config.path, e);
+        }
+
+        self.bytes += bytes;
+        self.queue.push_back(QueuedBatch { batch, bytes });
+
+        while self.bytes > self.config.max_bytes && self.queue.len() > 1 {
+            self.drop_oldest("queue full");
+        }
+        self.expire();
+        self.compact();
+    }
+
+    /// True once the backoff since the last failure has passed
+    pub fn ready(&self) -> bool {
+        return !self.queue.is_empty() && Instant::now() >= self.next_attempt;
+    }
+
+    pub fn front(&self) -> Option<&Vec<Metric>> {
+        return self.queue.front().map( |queued| &queued.batch.metrics );
+    }
+
+    /// The front batch has been delivered
+    pub fn pop(&mut self) {
+        if let Some(queued) = self.queue.pop_front() {
+            self.bytes -= queued.bytes;
+            self.dirty = true;
+        }
+        self.failures = 0;
+        self.next_attempt = Instant::now();
+    }
+
+    /// The front batch partly got there, so keep only the metrics that didn't
+    pub fn delivered_front(&mut self, delivered : usize) {
+        if delivered == 0 {
+            return;
+        }
+        if let Some(queued) = self.queue.front_mut() {
+            let delivered = delivered.min(queued.batch.metrics.len());
+            queued.batch.metrics.drain(..delivered);
+            let bytes = serde_json::to_string(&queued.batch).unwrap().len() as u64 + 1;
+            self.bytes = self.bytes - queued.bytes + bytes;
+            queued.bytes = bytes;
+            self.dirty = true;
+        }
+    }
+
+    /// The destination refused the front batch, so give up on it rather than hold up everything behind it
+    pub fn reject_front(&mut self) {
+        self.drop_oldest("rejected");
+    }
+
+    /// Count metrics given up on before they were ever queued
+    pub fn rejected(&mut self, metrics : usize) {
+        self.dropped_batches += 1;
+        self.dropped_metrics += metrics as u64;
+        log::warn!("{} - dropped batch of {} metrics (rejected), {} batches / {} metrics dropped so far",
+            self.name, metrics, self.dropped_batches, self.dropped_metrics);
+    }
+
+    /// A send failed, so hold off for a while before trying again
+    pub fn failed(&mut self) {
+        let delay = self.config.retry_initial.saturating_mul(1 << self.failures.min(16)).min(self.config.retry_max);
+        self.failures += 1;
+        self.next_attempt = Instant::now() + Duration::from_secs(delay);
+        log::info!("{} - {} batches queued, retrying in {}s", self.name, self.queue.len(), delay);
+    }
+
+    fn drop_oldest(&mut self, reason : &str) {
+        if let Some(queued) = self.queue.pop_front() {
+            self.bytes -= queued.bytes;
+            self.dirty = true;
+            self.dropped_batches += 1;
+            self.dropped_metrics += queued.batch.metrics.len() as u64;
+            log::warn!("{} - dropped batch of {} metrics ({}), {} batches / {} metrics dropped so far",
+                self.name, queued.batch.metrics.len(), reason, self.dropped_batches, self.dropped_metrics);
+        }
+    }
+
+    fn expire(&mut self) {
+        let cutoff = Utc::now() - chrono::Duration::seconds(self.config.max_age as i64);
+        while self.queue.front().map_or(false, |queued| queued.batch.queued < cutoff) {
+            self.drop_oldest("too old");
+        }
+        self.compact();
+    }
+
+    /// Rewrite the file without the batches that have gone
+    pub fn compact(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        let result = if self.queue.is_empty() {
+            match std::fs::remove_file(&self.config.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(())
+            }
+        } else {
+            let temp_path = format!("{}.tmp", self.config.path);
+            std::fs::File::create(&temp_path).and_then( |mut file| {
+                for queued in &self.queue {
+                    writeln!(file, "{}", serde_json::to_string(&queued.batch).unwrap())?;
+                }
+                file.sync_all()
+            }).and_then( |_| std::fs::rename(&temp_path, &self.config.path) )
+        };
+        match result {
+            Ok(_) => self.dirty = false,
+            Err(e) => log::error!("{} - unable to rewrite {} : {:?}", self.name, self.config.path, e)
+        }
+    }
+}
+
+
+#[cfg(test)]
+fn test_config(name : &str) -> BufferConfig {
+    let path = std::env::temp_dir().join(format!("homer_buffer_{}_{}.jsonl", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    return BufferConfig::example_config(&path.to_string_lossy());
+}
+
+#[test]
+fn test_buffer_survives_restart() {
+    let config = test_config("restart");
+    let mut buffer = DiskBuffer::open("test", config.clone());
+    assert!(buffer.is_empty());
+    buffer.push(&vec![Metric::new("a", "Temperature", 1.5)]);
+    buffer.push(&vec![Metric::new("a", "Temperature", 2.5), Metric::new("a", "Humidity", 40)]);
+    drop(buffer);
+
+    let mut buffer = DiskBuffer::open("test", config.clone());
+    assert_eq!(buffer.len(), 2);
+    assert!(buffer.ready());
+    assert_eq!(buffer.front().unwrap()[0].value, MetricValue::Float(1.5));
+    buffer.pop();
+    buffer.compact();
+    drop(buffer);
+
+    let mut buffer = DiskBuffer::open("test", config.clone());
+    assert_eq!(buffer.len(), 1);
+    assert_eq!(buffer.front().unwrap().len(), 2);
+    buffer.pop();
+    buffer.compact();
+    assert!(!std::path::Path::new(&config.path).exists());
+}
+
+#[test]
+fn test_buffer_size_cap() {
+    let mut config = test_config("size");
+    config.max_bytes = 400;
+    let mut buffer = DiskBuffer::open("test", config.clone());
+    for i in 0..10 {
+        buffer.push(&vec![Metric::new("a", "Count", i as i64)]);
+    }
+    assert!(buffer.len() < 10);
+    assert_eq!(buffer.dropped_batches, 10 - buffer.len() as u64);
+    assert_eq!(buffer.dropped_metrics, buffer.dropped_batches);
+    // Oldest go first
+    assert_eq!(buffer.front().unwrap()[0].value, MetricValue::Integer(buffer.dropped_batches as i64));
+    assert_eq!(DiskBuffer::open("test", config.clone()).len(), buffer.len());
+    std::fs::remove_file(&config.path).unwrap();
+}
+
+#[test]
+fn test_buffer_age_cap() {
+    let config = test_config("age");
+    let old = Batch { queued: Utc::now() - chrono::Duration::days(30), metrics: vec![Metric::new("a", "Count", 1)] };
+    let new = Batch { queued: Utc::now(), metrics: vec![Metric::new("a", "Count", 2)] };
+    std::fs::write(&config.path, format!("{}\n{}\n", serde_json::to_string(&old).unwrap(), serde_json::to_string(&new).unwrap())).unwrap();
+
+    let buffer = DiskBuffer::open("test", config.clone());
+    assert_eq!(buffer.len(), 1);
+    assert_eq!(buffer.dropped_metrics, 1);
+    assert_eq!(buffer.front().unwrap()[0].value, MetricValue::Integer(2));
+    std::fs::remove_file(&config.path).unwrap();
+}
+
+#[test]
+fn test_buffer_backoff() {
+    let mut config = test_config("backoff");
+    config.retry_initial = 60;
+    config.retry_max = 100;
+    let mut buffer = DiskBuffer::open("test", config.clone());
+    let wait = |buffer : &DiskBuffer| buffer.next_attempt.saturating_duration_since(Instant::now()).as_secs_f64();
+    buffer.push(&vec![Metric::new("a", "Count", 1)]);
+    assert!(buffer.ready());
+    buffer.failed();
+    assert!(!buffer.ready());
+    assert!(wait(&buffer) > 59.0 && wait(&buffer) <= 60.0);
+    // Doubles, up to retry_max
+    buffer.failed();
+    assert!(wait(&buffer) > 99.0 && wait(&buffer) <= 100.0);
+    assert_eq!(buffer.len(), 1);
+
+    // Delivering resets the backoff, so the next batch goes straight away
+    buffer.pop();
+    assert!(buffer.is_empty());
+    assert_eq!(buffer.failures, 0);
+    buffer.push(&vec![Metric::new("a", "Count", 2)]);
+    assert!(buffer.ready());
+    buffer.pop();
+    buffer.compact();
+    assert!(!std::path::Path::new(&config.path).exists());
+}
+
+#[test]
+fn test_buffer_partial_and_rejected() {
+    let config = test_config("partial");
+    let mut buffer = DiskBuffer::open("test", config.clone());
+    buffer.push(&vec![Metric::new("a", "Count", 1), Metric::new("a", "Count", 2), Metric::new("a", "Count", 3)]);
+    buffer.push(&vec![Metric::new("b", "Count", 4)]);
+    buffer.delivered_front(2);
+    buffer.compact();
+    assert_eq!(buffer.front().unwrap().len(), 1);
+    assert_eq!(buffer.front().unwrap()[0].value, MetricValue::Integer(3));
+
+    // What's on disk matches, so a restart doesn't send the delivered ones again
+    let mut reopened = DiskBuffer::open("test", config.clone());
+    assert_eq!(reopened.front().unwrap().len(), 1);
+    assert_eq!(reopened.bytes, buffer.bytes);
+
+    reopened.reject_front();
+    reopened.rejected(5);
+    assert_eq!(reopened.front().unwrap()[0].value, MetricValue::Integer(4));
+    assert_eq!(reopened.dropped_batches, 2);
+    assert_eq!(reopened.dropped_metrics, 6);
+    std::fs::remove_file(&config.path).unwrap();
+}
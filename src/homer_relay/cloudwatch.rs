@@ -3,47 +3,166 @@ use rusoto_cloudwatch::*;
 use rusoto_cloudwatch as cw;
 use rusoto_core::Region;
 pub use super::core::*;
+use super::buffer::BufferConfig;
 use async_trait::async_trait;
+use chrono::SecondsFormat;
+use std::str::FromStr;
+use std::collections::BTreeMap;
+
+/// PutMetricData won't take more than this many datums in one request
+pub const MAX_DATUMS_PER_REQUEST : usize = 1000;
+
+/// Nor more than this many dimensions on a metric, Object included
+pub const MAX_DIMENSIONS : usize = 30;
+
+fn default_batch_size() -> usize {
+    return MAX_DATUMS_PER_REQUEST;
+}
 
 #[derive(Deserialize,Serialize)]
 pub struct DestinationCloudwatchConfig {
     pub namespace: String,
+    /// e.g. "eu-west-2"
     pub region: String,
-    pub profile: String
+    /// Profile from ~/.aws/credentials, otherwise the default chain (environment, instance role...) is used
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Send somewhere other than AWS, e.g. "http://localhost:4566" for LocalStack
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Store at 1 second rather than 1 minute resolution (charged at a higher rate)
+    #[serde(default)]
+    pub high_resolution: bool,
+    /// Added to every metric alongside Object, e.g. Site = "Home"
+    #[serde(default)]
+    pub dimensions: BTreeMap<String, String>,
+    /// Metrics per PutMetricData request, larger reports are split
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Keep metrics on disk while CloudWatch is unreachable
+    #[serde(default)]
+    pub buffer: Option<BufferConfig>,
 }
 
 impl DestinationCloudwatchConfig {
     pub fn example_config()->DestinationCloudwatchConfig {
         return DestinationCloudwatchConfig {
             namespace:"TestCloudwatchNamespace".to_string(),
-            profile:"edonica".to_string(),
-            region:"eu-west-2".to_string()
+            profile:Some("edonica".to_string()),
+            region:"eu-west-2".to_string(),
+            endpoint:None,
+            high_resolution: false,
+            dimensions: vec![("Site".to_string(), "Home".to_string())].into_iter().collect(),
+            batch_size: default_batch_size(),
+            buffer: Some(BufferConfig::example_config("cloudwatch.buffer.jsonl"))
+        }
+    }
+
+    /// The configured region, or a custom one pointing at the endpoint
+    pub fn region(&self) -> Result<Region> {
+        if let Some(endpoint) = &self.endpoint {
+            return Ok(Region::Custom {
+                name: self.region.clone(),
+                endpoint: endpoint.clone()
+            });
+        }
+        return Region::from_str(&self.region).map_err( |e| Error::Config(format!("bad region {} : {}", self.region, e)) );
+    }
+
+    /// Catch what CloudWatch would refuse every request for
+    pub fn validate(&self) -> Result<()> {
+        if self.dimensions.len() + 1 > MAX_DIMENSIONS {
+            return Err(Error::Config(format!("{} dimensions, at most {} can go with Object", self.dimensions.len(), MAX_DIMENSIONS - 1)));
         }
+        return Ok(());
+    }
+
+    /// Turn a metric into a CloudWatch datum, None if it isn't numeric
+    pub fn datum(&self, metric : &Metric) -> Option<MetricDatum> {
+        let value = metric.value.as_f64()?;
+        let mut dimensions = vec![Dimension {
+            name:"Object".to_string(),
+            value: metric.object.clone()
+        }];
+        for (name, value) in &self.dimensions {
+            dimensions.push(Dimension { name: name.clone(), value: value.clone() });
+        }
+        return Some(MetricDatum {
+            metric_name: metric.property.clone(),
+            storage_resolution: Some(if self.high_resolution { 1 } else { 60 }),
+            value: Some(value),
+            dimensions: Some(dimensions),
+            counts:None,
+            statistic_values:None,
+            timestamp: metric.timestamp.map( |t| t.to_rfc3339_opts(SecondsFormat::Millis, true) ),
+            unit: metric.unit.as_ref().and_then( |unit| standard_unit(unit) ).map( |unit| unit.to_string() ),
+            values:None
+        });
     }
 }
 
+/// The CloudWatch StandardUnit for one of our units.  Anything CloudWatch has no name for (°C, hPa...) is sent without a unit.
+pub fn standard_unit(unit : &str) -> Option<&'static str> {
+    return match unit {
+        "s" => Some("Seconds"),
+        "ms" => Some("Milliseconds"),
+        "us" | "µs" => Some("Microseconds"),
+        "B" => Some("Bytes"),
+        "kB" | "KB" | "KiB" => Some("Kilobytes"),
+        "MB" | "MiB" => Some("Megabytes"),
+        "GB" | "GiB" => Some("Gigabytes"),
+        "TB" | "TiB" => Some("Terabytes"),
+        "bit" => Some("Bits"),
+        "kbit" => Some("Kilobits"),
+        "Mbit" => Some("Megabits"),
+        "Gbit" => Some("Gigabits"),
+        "%" => Some("Percent"),
+        "count" => Some("Count"),
+        "B/s" => Some("Bytes/Second"),
+        "kB/s" => Some("Kilobytes/Second"),
+        "MB/s" => Some("Megabytes/Second"),
+        "bit/s" => Some("Bits/Second"),
+        "kbit/s" => Some("Kilobits/Second"),
+        "Mbit/s" => Some("Megabits/Second"),
+        "/s" => Some("Count/Second"),
+        _ => None
+    };
+}
+
 #[typetag::serde(name = "cloudwatch")]
 impl DestinationConfig for DestinationCloudwatchConfig {
     fn name(&self) -> String {
         return String::from("cloudwatch");
     }
 
-    fn init(self : Box<Self> ) -> Box<dyn Destination> {
-        let n = self.name().clone();
+    fn buffer(&self) -> Option<BufferConfig> {
+        return self.buffer.clone();
+    }
 
-        let mut profile = rusoto_core::credential::ProfileProvider::new().unwrap();
-        let request_dispatcher = rusoto_core::request::HttpClient::new().expect("failed to create request dispatcher");
-        profile.set_profile(self.profile.clone());
+    fn init(self : Box<Self> ) -> Result<Box<dyn Destination>> {
+        let n = self.name().clone();
+        self.validate()?;
 
-        let region = Region::EuWest2; //Region::from_str(self.region).unwrap();
+        let request_dispatcher = rusoto_core::request::HttpClient::new().map_err( |e| Error::Config(format!("AWS request dispatcher : {:?}", e)) )?;
+        let region = self.region()?;
 
-        let client = cw::CloudWatchClient::new_with(request_dispatcher, profile, region);
+        let client = match &self.profile {
+            Some(profile_name) => {
+                let mut profile = rusoto_core::credential::ProfileProvider::new().map_err( |e| Error::Config(format!("AWS profile : {:?}", e)) )?;
+                profile.set_profile(profile_name.clone());
+                cw::CloudWatchClient::new_with(request_dispatcher, profile, region)
+            }
+            None => {
+                let credentials = rusoto_core::credential::DefaultCredentialsProvider::new().map_err( |e| Error::Config(format!("AWS credentials : {:?}", e)) )?;
+                cw::CloudWatchClient::new_with(request_dispatcher, credentials, region)
+            }
+        };
 
-        return Box::new( DestinationCloudwatch{
+        return Ok(Box::new( DestinationCloudwatch{
             config : self,
             name: n,
             client 
-        } )
+        } ))
 
     }
 }
@@ -62,32 +181,32 @@ impl Destination for DestinationCloudwatch {
     }
 
 
-    async fn report(&mut self, metrics: &Vec<super::core::Metric>) {
+    async fn report(&mut self, metrics: &Vec<super::core::Metric>) -> Result<()> {
 
         println!("{} Sending {} metrics",self.name(), metrics.len());
 
-        let datums: Vec<MetricDatum> = metrics.into_iter().map( |metric| MetricDatum {
-            metric_name: metric.property.clone(),
-            storage_resolution: Some(60),
-            value: metric.value.parse().ok(),
-            dimensions: Some(vec![Dimension {
-                name:"Object".to_string(),
-                value: metric.object.clone()
-            }]),
-            counts:None,
-            statistic_values:None,
-            timestamp:None,
-            unit:None,
-            values:None
+        // Each datum with the index of its metric, so a failure can say how much of the batch got there
+        let datums: Vec<(usize, MetricDatum)> = metrics.into_iter().enumerate().filter_map( |(index, metric)| {
+            let datum = self.config.datum(metric);
+            if datum.is_none() {
+                println!("{} skipping non-numeric {}/{} = {}",self.name(), metric.object, metric.property, metric.value);
+            }
+            datum.map( |datum| (index, datum) )
         }).collect();
 
-        let input = PutMetricDataInput{
-            metric_data: datums,
-            namespace: self.config.namespace.clone(),
-        };
+        // A zero batch size would never send anything
+        let batch_size = self.config.batch_size.max(1).min(MAX_DATUMS_PER_REQUEST);
+        for chunk in datums.chunks(batch_size) {
+            let input = PutMetricDataInput{
+                metric_data: chunk.iter().map( |(_, datum)| datum.clone() ).collect(),
+                namespace: self.config.namespace.clone(),
+            };
 
-        self.client.put_metric_data(input).await.expect("Send metric to CloudWatch");
-        println!("{} sent",self.name());
+            // Earlier chunks were accepted, so only this one and those after it need sending again
+            self.client.put_metric_data(input).await.map_err( |e| Error::send(format!("{:?}", e)).with_delivered(chunk[0].0) )?;
+            println!("{} sent {}",self.name(), chunk.len());
+        }
+        return Ok(());
     }
 
     fn shutdown(&mut self) -> Option<std::thread::JoinHandle<()>> {
@@ -95,3 +214,45 @@ impl Destination for DestinationCloudwatch {
     }
 }
 
+
+#[test]
+fn test_region() {
+    let mut config = DestinationCloudwatchConfig::example_config();
+    assert_eq!(config.region().unwrap(), Region::EuWest2);
+
+    config.region = "mars-north-1".to_string();
+    assert!(config.region().is_err());
+
+    config.region = "us-east-1".to_string();
+    config.endpoint = Some("http://localhost:4566".to_string());
+    assert_eq!(config.region().unwrap(), Region::Custom { name: "us-east-1".to_string(), endpoint: "http://localhost:4566".to_string() });
+}
+
+#[test]
+fn test_datum() {
+    let mut config = DestinationCloudwatchConfig::example_config();
+    let metric = Metric::new("Pi", "DiskFree", 12.5).with_unit("GB");
+    let datum = config.datum(&metric).unwrap();
+    assert_eq!(datum.metric_name, "DiskFree");
+    assert_eq!(datum.storage_resolution, Some(60));
+    assert_eq!(datum.unit, Some("Gigabytes".to_string()));
+    assert_eq!(datum.dimensions.unwrap().iter().map( |d| (d.name.as_str(), d.value.as_str()) ).collect::<Vec<_>>(),
+        vec![("Object", "Pi"), ("Site", "Home")]);
+
+    config.high_resolution = true;
+    let temperature = config.datum(&Metric::new("FishTank", "Temperature", 24.5).with_unit("°C")).unwrap();
+    assert_eq!(temperature.storage_resolution, Some(1));
+    assert_eq!(temperature.unit, None);
+
+    assert!(config.datum(&Metric::new("Remote", "Button", "press")).is_none());
+}
+
+#[test]
+fn test_validate_dimensions() {
+    let mut config = DestinationCloudwatchConfig::example_config();
+    assert!(config.validate().is_ok());
+    config.dimensions = (0..MAX_DIMENSIONS).map( |i| (format!("D{}", i), "x".to_string()) ).collect();
+    assert!(config.validate().is_err());
+    config.dimensions.remove("D0");
+    assert!(config.validate().is_ok());
+}
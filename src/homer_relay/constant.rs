@@ -7,7 +7,11 @@ use async_trait::async_trait;
 pub struct SourceConstantConfig {
     object : String,
     property : String,
-    value : f64
+    value : MetricValue,
+    #[serde(default)]
+    unit : Option<String>,
+    #[serde(default)]
+    interval : Option<u64>
 }
 
 pub struct SourceConstant {
@@ -20,7 +24,9 @@ impl SourceConstantConfig {
         return SourceConstantConfig {
             object: "TestObject".to_string(),
             property: "Temperature".to_string(),
-            value: 2.34
+            value: MetricValue::Float(2.34),
+            unit: Some("°C".to_string()),
+            interval: None
         }
     }
 }
@@ -30,11 +36,14 @@ impl SourceConfig for SourceConstantConfig {
     fn name(&self) -> String {
         return format!("constant Object {} : {}{}", self.object, self.property, self.value);
     }
-    fn init(self : Box<Self>) -> Box<dyn Source> {
-        return Box::new( SourceConstant{
+    fn interval(&self) -> Option<u64> {
+        return self.interval;
+    }
+    fn init(self : Box<Self>) -> Result<Box<dyn Source>> {
+        return Ok(Box::new( SourceConstant{
             name: self.name(),
             config: self
-        } )
+        } ))
     }
 }
 
@@ -44,14 +53,64 @@ impl Source for SourceConstant {
     fn name(&self) -> &String { 
         return &self.name;
     }
-    async fn poll(&mut self) -> Vec<Metric> {
+    async fn poll(&mut self) -> Result<Vec<Metric>> {
         println!("{} - returning value", self.name());
-        return vec![Metric {
+        return Ok(vec![Metric {
             object: self.config.object.clone(),
             property: self.config.property.clone(),
-            value: format!("{}", self.config.value)
-        }]
+            value: self.config.value.clone(),
+            unit: self.config.unit.clone(),
+            timestamp: None,
+            source: None
+        }])
         
     }
 }
 
+
+#[tokio::test]
+async fn test_constant_value_kinds() {
+    let config : Config = toml::from_str(r#"
+        destinations = []
+        [[sources]]
+        type = "constant"
+        object = "a"
+        property = "float"
+        value = 2.5
+        unit = "°C"
+        [[sources]]
+        type = "constant"
+        object = "a"
+        property = "integer"
+        value = 3
+        [[sources]]
+        type = "constant"
+        object = "a"
+        property = "boolean"
+        value = true
+        [[sources]]
+        type = "constant"
+        object = "a"
+        property = "text"
+        value = "open"
+    "#).unwrap();
+    let names : Vec<String> = config.sources.iter().map( |s| s.name() ).collect();
+    assert_eq!(names, vec![
+        "constant Object a : float2.5",
+        "constant Object a : integer3",
+        "constant Object a : booleantrue",
+        "constant Object a : textopen",
+    ]);
+
+    let mut polled = vec![];
+    for source_config in config.sources {
+        polled.extend(source_config.init().unwrap().poll().await.unwrap());
+    }
+    let values : Vec<(MetricValue, Option<String>)> = polled.into_iter().map( |m| (m.value, m.unit) ).collect();
+    assert_eq!(values, vec![
+        (MetricValue::Float(2.5), Some("°C".to_string())),
+        (MetricValue::Integer(3), None),
+        (MetricValue::Boolean(true), None),
+        (MetricValue::Text("open".to_string()), None),
+    ]);
+}
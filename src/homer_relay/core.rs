@@ -1,26 +1,182 @@
 use serde::{Serialize, Deserialize};
 use async_trait::async_trait;
+use std::time::{Duration, Instant};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use chrono::{DateTime, Utc};
+use super::buffer::{BufferConfig, DiskBuffer};
+pub use super::error::{Error, Result};
 
+/// A single reading.  Untagged so that config files can just say value = 1.5, value = true etc.
+#[derive(Deserialize,Serialize,Clone,Debug,PartialEq)]
+#[serde(untagged)]
+pub enum MetricValue {
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl MetricValue {
+    /// Numeric view of the value, for destinations that can only take numbers
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            MetricValue::Boolean(b) => Some(if *b { 1.0 } else { 0.0 }),
+            MetricValue::Integer(i) => Some(*i as f64),
+            MetricValue::Float(f) => Some(*f),
+            MetricValue::Text(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for MetricValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetricValue::Boolean(b) => write!(f, "{}", b),
+            MetricValue::Integer(i) => write!(f, "{}", i),
+            MetricValue::Float(v) => write!(f, "{}", v),
+            MetricValue::Text(t) => write!(f, "{}", t),
+        }
+    }
+}
+
+impl From<bool> for MetricValue {
+    fn from(value: bool) -> MetricValue { MetricValue::Boolean(value) }
+}
+impl From<i64> for MetricValue {
+    fn from(value: i64) -> MetricValue { MetricValue::Integer(value) }
+}
+impl From<f64> for MetricValue {
+    fn from(value: f64) -> MetricValue { MetricValue::Float(value) }
+}
+impl From<String> for MetricValue {
+    fn from(value: String) -> MetricValue { MetricValue::Text(value) }
+}
+impl From<&str> for MetricValue {
+    fn from(value: &str) -> MetricValue { MetricValue::Text(value.to_string()) }
+}
+
+#[derive(Deserialize,Serialize,Clone,Debug)]
 pub struct Metric {
     pub object: String,
     pub property: String,
-    pub value: String,
+    pub value: MetricValue,
+    /// Unit symbol, e.g. "°C", "%", "hPa", "V"
+    pub unit: Option<String>,
+    /// When the reading was captured.  Filled in by the Manager if the source doesn't know better.
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Name of the source that produced the reading, filled in by the Manager
+    pub source: Option<String>,
+}
+
+impl Metric {
+    pub fn new<V : Into<MetricValue>>(object : &str, property : &str, value : V) -> Metric {
+        return Metric {
+            object: object.to_string(),
+            property: property.to_string(),
+            value: value.into(),
+            unit: None,
+            timestamp: None,
+            source: None,
+        };
+    }
+
+    pub fn with_unit(mut self, unit : &str) -> Metric {
+        self.unit = Some(unit.to_string());
+        return self;
+    }
+
+    pub fn with_timestamp(mut self, timestamp : DateTime<Utc>) -> Metric {
+        self.timestamp = Some(timestamp);
+        return self;
+    }
+
+    /// Stamp a metric with its provenance, keeping any capture time the source already set
+    pub fn stamp(&mut self, source : &str, now : DateTime<Utc>) {
+        if self.timestamp.is_none() {
+            self.timestamp = Some(now);
+        }
+        if self.source.is_none() {
+            self.source = Some(source.to_string());
+        }
+    }
+}
+
+/// Things the relay can be asked to do while it's running, e.g. over MQTT
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum RelayCommand {
+    /// Poll every source now
+    Poll,
+    /// Re-read the configuration file and start again
+    Reload,
+    /// Restart scanning on sources that scan, e.g. bluetooth
+    Scan,
+    /// Describe the sources, destinations and queues
+    Status,
+}
+
+impl RelayCommand {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            RelayCommand::Poll => "poll",
+            RelayCommand::Reload => "reload",
+            RelayCommand::Scan => "scan",
+            RelayCommand::Status => "status",
+        };
+    }
+}
+
+impl std::str::FromStr for RelayCommand {
+    type Err = Error;
+    fn from_str(name : &str) -> Result<RelayCommand> {
+        return match name {
+            "poll" => Ok(RelayCommand::Poll),
+            "reload" => Ok(RelayCommand::Reload),
+            "scan" => Ok(RelayCommand::Scan),
+            "status" => Ok(RelayCommand::Status),
+            _ => Err(Error::Parse(format!("unknown command {}", name)))
+        };
+    }
+}
+
+/// A command on its way to the Manager, with somewhere to send the answer
+pub struct CommandRequest {
+    pub command : RelayCommand,
+    pub reply : Box<dyn FnOnce(String) + Send>,
+}
+
+pub type CommandSender = tokio::sync::mpsc::UnboundedSender<CommandRequest>;
+
+/// Why Manager::run returned
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum RunOutcome {
+    Stopped,
+    Reload,
 }
 
 #[typetag::serde(tag = "type")]
 pub trait DestinationConfig {
     fn name(&self) -> String;
-    fn init(self : Box<Self>) -> Box<dyn Destination>;
+    fn init(self : Box<Self>) -> Result<Box<dyn Destination>>;
+    /// Where to keep batches that couldn't be sent, if this destination wants store and forward
+    fn buffer(&self) -> Option<BufferConfig> {
+        return None;
+    }
 }
 
 #[async_trait]
 pub trait Destination {
     fn name(&self) -> &String;
-    async fn report(&mut self, metrics: &Vec<Metric>) -> ();
+    /// Send a batch, returning an error if it didn't get there
+    async fn report(&mut self, metrics: &Vec<Metric>) -> Result<()>;
 
     fn test(&mut self) -> () {
         println!("{} : No tests applicable", self.name());
     }
+    /// Somewhere to pass on commands received from outside, for destinations that listen for them
+    fn accept_commands(&mut self, _commands : CommandSender) {
+    }
     fn shutdown(&mut self) -> Option<std::thread::JoinHandle<()>> {
         return Option::None;
     }
@@ -29,80 +185,307 @@ pub trait Destination {
 #[typetag::serde(tag = "type")]
 pub trait SourceConfig {
     fn name(&self) -> String;
-    fn init(self : Box<Self>) -> Box<dyn Source>;
+    fn init(self : Box<Self>) -> Result<Box<dyn Source>>;
+    /// Seconds between polls of this source, if it overrides the global poll_interval
+    fn interval(&self) -> Option<u64> {
+        return None;
+    }
 }
 
 #[async_trait]
 pub trait Source {
     fn name(&self) -> &String;
-    async fn poll(&mut self) -> Vec<Metric>;
+    async fn poll(&mut self) -> Result<Vec<Metric>>;
+    /// Restart any scanning, returning false for sources that don't scan
+    fn scan(&mut self) -> Result<bool> {
+        return Ok(false);
+    }
     fn shutdown(&mut self) -> Option<std::thread::JoinHandle<()>> {
         return Option::None;
     }
 }
 
 
+pub const DEFAULT_POLL_INTERVAL : u64 = 60;
+
+fn default_poll_interval() -> u64 {
+    return DEFAULT_POLL_INTERVAL;
+}
+
 #[derive(Deserialize,Serialize)]
 pub struct Config {
+    /// Seconds between polls for any source that doesn't set its own interval
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval : u64,
     pub destinations : Vec<Box<dyn DestinationConfig>>,
     pub sources : Vec<Box<dyn SourceConfig>>
 }
 
 pub struct Manager {
     pub destinations : Vec<Box<dyn Destination>>, 
-    pub sources : Vec<Box<dyn Source>> 
+    /// Store and forward queue for each entry in destinations, if configured
+    pub buffers : Vec<Option<DiskBuffer>>,
+    pub sources : Vec<Box<dyn Source>>,
+    /// Poll interval for each entry in sources
+    pub intervals : Vec<Duration>,
+    pub commands : Option<tokio::sync::mpsc::UnboundedReceiver<CommandRequest>>,
+    pub started : Instant,
 }
 
 impl Manager {
     pub fn create( config : Config ) -> Manager {
         println!("manager - creating from configuration");
+        let (command_sender, command_receiver) = tokio::sync::mpsc::unbounded_channel();
         let mut manager = Manager {
             destinations: vec![],
-            sources: vec![]
+            buffers: vec![],
+            sources: vec![],
+            intervals: vec![],
+            commands: Some(command_receiver),
+            started: Instant::now(),
         };
         for destination_conf in config.destinations {
-            println!("manager - creating {}", destination_conf.name());
-            let destination = destination_conf.init();
+            let name = destination_conf.name();
+            println!("manager - creating {}", name);
+            let buffer_config = destination_conf.buffer();
+            let mut destination = match destination_conf.init() {
+                Ok(destination) => destination,
+                Err(e) => {
+                    log::error!("manager - unable to create {}, leaving it out : {}", name, e);
+                    continue;
+                }
+            };
             println!("manager - created {}", destination.name());
+            destination.accept_commands(command_sender.clone());
+            manager.buffers.push(buffer_config.map( |config| DiskBuffer::open(destination.name(), config) ));
             manager.destinations.push(destination)
         }
         for source_conf in config.sources {
-            println!("manager - creating {}", source_conf.name());
-            let source = source_conf.init();
-            println!("manager - created {}", source.name());
-            manager.sources.push(source)
+            let name = source_conf.name();
+            println!("manager - creating {}", name);
+            let interval = source_conf.interval().unwrap_or(config.poll_interval).max(1);
+            let source = match source_conf.init() {
+                Ok(source) => source,
+                Err(e) => {
+                    log::error!("manager - unable to create {}, leaving it out : {}", name, e);
+                    continue;
+                }
+            };
+            println!("manager - created {}, polling every {}s", source.name(), interval);
+            manager.sources.push(source);
+            manager.intervals.push(Duration::from_secs(interval));
         }
         return manager;
     }
 
     pub async fn send_metrics(&mut self, metrics: &Vec<Metric>) -> () {
-        for destination in &mut self.destinations {
-            println!("manager - sending test metric to {}", destination.name());
-            destination.report( &metrics ).await;
+        for (destination, buffer) in self.destinations.iter_mut().zip(self.buffers.iter_mut()) {
+            println!("manager - sending {} metrics to {}", metrics.len(), destination.name());
+            match buffer {
+                None => {
+                    if let Err(e) = destination.report( &metrics ).await {
+                        println!("manager - {} failed, dropping {} metrics : {}", destination.name(), metrics.len(), e);
+                    }
+                }
+                Some(buffer) => {
+                    if buffer.is_empty() {
+                        if let Err(e) = destination.report( &metrics ).await {
+                            // Only keep what didn't get there, and only if it's worth trying again
+                            let unsent = metrics[e.delivered().min(metrics.len())..].to_vec();
+                            if !e.is_retryable() {
+                                println!("manager - {} rejected {} metrics, dropping them : {}", destination.name(), unsent.len(), e);
+                                buffer.rejected(unsent.len());
+                            } else if !unsent.is_empty() {
+                                println!("manager - {} failed, queueing {} metrics : {}", destination.name(), unsent.len(), e);
+                                buffer.push(&unsent);
+                                buffer.failed();
+                            }
+                        }
+                    } else {
+                        // Keep things in order by going to the back of the queue
+                        buffer.push(metrics);
+                        Manager::replay(destination, buffer).await;
+                    }
+                }
+            }
         }  
     }
 
+    /// Send queued batches, oldest first, until the queue is empty or the destination fails again.
+    /// A batch the destination refuses is dropped so it doesn't hold up the rest.
+    async fn replay(destination : &mut Box<dyn Destination>, buffer : &mut DiskBuffer) {
+        while buffer.ready() {
+            let batch = match buffer.front() {
+                Some(batch) => batch.clone(),
+                None => break
+            };
+            match destination.report( &batch ).await {
+                Ok(_) => {
+                    buffer.pop();
+                }
+                Err(e) if !e.is_retryable() => {
+                    println!("manager - {} rejected a queued batch, dropping it : {}", destination.name(), e);
+                    buffer.delivered_front(e.delivered());
+                    buffer.reject_front();
+                }
+                Err(e) => {
+                    println!("manager - {} still failing : {}", destination.name(), e);
+                    buffer.delivered_front(e.delivered());
+                    buffer.failed();
+                    break;
+                }
+            }
+        }
+        buffer.compact();
+    }
+
     pub async fn test(&mut self) {
         println!("manager - sending test metric to all destinations");
-        let metrics = vec![Metric {
-            object: String::from("TestSensor"),
-            property: String::from("Temperature"),
-            value: String::from("1.23"),
-        }];
+        let mut metrics = vec![Metric::new("TestSensor", "Temperature", 1.23).with_unit("°C")];
+        for metric in &mut metrics {
+            metric.stamp("test", Utc::now());
+        }
         self.send_metrics(&metrics).await;
     }       
 
-    pub async fn run(&mut self) {
-        let mut all_metrics : Vec<Metric>=vec![];
+    pub async fn poll_source(&mut self, index : usize) -> () {
+        let source = &mut self.sources[index];
+        println!("manager - polling {}", source.name());
+        let mut metrics = match source.poll().await {
+            Ok(metrics) => metrics,
+            Err(e) => {
+                log::error!("manager - polling {} failed : {}", source.name(), e);
+                return;
+            }
+        };
+        let now = Utc::now();
+        for metric in &mut metrics {
+            metric.stamp(source.name(), now);
+        }
+        if metrics.len() > 0 {
+            self.send_metrics(&metrics).await;
+        }
+    }
+
+    pub fn status(&self) -> String {
+        let destinations : Vec<serde_json::Value> = self.destinations.iter().zip(self.buffers.iter()).map( |(destination, buffer)| {
+            serde_json::json!({
+                "name": destination.name(),
+                "queued": buffer.as_ref().map( |buffer| buffer.len() ),
+                "dropped": buffer.as_ref().map( |buffer| buffer.dropped_metrics ),
+            })
+        }).collect();
+        return serde_json::json!({
+            "uptime": self.started.elapsed().as_secs(),
+            "sources": self.sources.iter().map( |source| source.name().clone() ).collect::<Vec<String>>(),
+            "destinations": destinations,
+        }).to_string();
+    }
 
-        for source in &mut self.sources {
-            println!("manager - shutting down {}", source.name());
-            let metrics = source.poll().await;
-            for metric in metrics {
-                all_metrics.push(metric);
+    /// Carry out a command, returning the answer and whether to stop for a reload
+    async fn handle_command(&mut self, command : RelayCommand) -> (String, bool) {
+        println!("manager - received command {}", command.name());
+        match command {
+            RelayCommand::Poll => {
+                for index in 0..self.sources.len() {
+                    self.poll_source(index).await;
+                }
+                return (format!("polled {} sources", self.sources.len()), false);
+            }
+            RelayCommand::Reload => {
+                return ("reloading configuration".to_string(), true);
+            }
+            RelayCommand::Scan => {
+                let mut scanning = vec![];
+                for source in &mut self.sources {
+                    match source.scan() {
+                        Ok(true) => scanning.push(source.name().clone()),
+                        Ok(false) => (),
+                        Err(e) => log::error!("manager - unable to restart scan on {} : {}", source.name(), e),
+                    }
+                }
+                if scanning.is_empty() {
+                    return ("no sources scan".to_string(), false);
+                }
+                return (format!("scanning on {}", scanning.join(", ")), false);
+            }
+            RelayCommand::Status => {
+                return (self.status(), false);
+            }
+        }
+    }
+
+    /// Poll each source on its own schedule until a message arrives on ctrl_c or a reload is asked for, then shut down
+    pub async fn run(&mut self, ctrl_c : crossbeam_channel::Receiver<()>) -> RunOutcome {
+        // The Ctrl-C channel is a blocking one, so wait for it on a worker thread.  It gives up once we're
+        // done so that it doesn't take the next Ctrl-C from a Manager created by a reload.
+        let finished = Arc::new(AtomicBool::new(false));
+        let finished_flag = Arc::clone(&finished);
+        let mut stop = tokio::task::spawn_blocking( move || {
+            while !finished_flag.load(Ordering::SeqCst) {
+                match ctrl_c.recv_timeout(Duration::from_millis(250)) {
+                    Err(crossbeam_channel::RecvTimeoutError::Timeout) => (),
+                    _ => return true
+                }
+            }
+            return false;
+        });
+
+        let mut commands = self.commands.take();
+        let mut outcome = RunOutcome::Stopped;
+        let start = Instant::now();
+        let mut next_poll : Vec<Instant> = vec![start; self.sources.len()];
+        if self.sources.is_empty() {
+            println!("manager - no sources configured, waiting for Ctrl-C");
+        }
+
+        loop {
+            let next = next_poll.iter().enumerate().min_by_key( |(_, due)| **due ).map( |(index, due)| (index, *due) );
+            // With no sources there's nothing to sleep until, so just wait for Ctrl-C or a command
+            let (index, due) = next.unwrap_or((usize::MAX, start));
+            tokio::select! {
+                _ = &mut stop => {
+                    println!("manager - stopping");
+                    break;
+                }
+                Some(request) = async { match &mut commands { Some(commands) => commands.recv().await, None => None } } => {
+                    // A poll can take a while, e.g. a BLE source waiting on a device, so keep listening for Ctrl-C
+                    let (answer, reload) = tokio::select! {
+                        handled = self.handle_command(request.command) => handled,
+                        _ = &mut stop => {
+                            println!("manager - stopping");
+                            break;
+                        }
+                    };
+                    (request.reply)(answer);
+                    if reload {
+                        println!("manager - stopping to reload");
+                        outcome = RunOutcome::Reload;
+                        break;
+                    }
+                }
+                _ = tokio::time::sleep_until(tokio::time::Instant::from_std(due)), if index != usize::MAX => {
+                    tokio::select! {
+                        _ = self.poll_source(index) => (),
+                        _ = &mut stop => {
+                            println!("manager - stopping");
+                            break;
+                        }
+                    }
+                    // If a poll overran, skip the missed slots rather than polling in a burst
+                    let interval = self.intervals[index];
+                    let mut following = due + interval;
+                    let now = Instant::now();
+                    while following <= now {
+                        following += interval;
+                    }
+                    next_poll[index] = following;
+                }
             }
         }
-        self.send_metrics(&all_metrics).await;
+        finished.store(true, Ordering::SeqCst);
+        self.shutdown();
+        return outcome;
     }
 
     pub fn shutdown(&mut self) {
@@ -112,7 +495,9 @@ impl Manager {
             match destination.shutdown() {
                 Some(join_handle) => {
                     println!("manager - {} is busy, waiting", destination.name() );
-                    join_handle.join().unwrap();
+                    if join_handle.join().is_err() {
+                        log::error!("manager - {} worker thread panicked", destination.name());
+                    }
                     println!("manager - {} is done", destination.name() );
                 }
                 None => {
@@ -124,7 +509,9 @@ impl Manager {
             match source.shutdown() {
                 Some(join_handle) => {
                     println!("manager - {} is busy, waiting", source.name() );
-                    join_handle.join().unwrap();
+                    if join_handle.join().is_err() {
+                        log::error!("manager - {} worker thread panicked", source.name());
+                    }
                     println!("manager - {} is done", source.name() );
                 }
                 None => {
@@ -134,3 +521,144 @@ impl Manager {
     }
 }
 
+
+/// A destination that answers each report from a script, remembering what it was sent
+#[cfg(test)]
+pub(crate) struct ScriptedDestination {
+    pub name : String,
+    pub results : std::collections::VecDeque<Result<()>>,
+    pub received : Arc<std::sync::Mutex<Vec<MetricValue>>>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Destination for ScriptedDestination {
+    fn name(&self) -> &String {
+        return &self.name;
+    }
+    async fn report(&mut self, metrics: &Vec<Metric>) -> Result<()> {
+        let result = self.results.pop_front().unwrap_or(Ok(()));
+        let delivered = match &result {
+            Ok(_) => metrics.len(),
+            Err(e) => e.delivered()
+        };
+        self.received.lock().unwrap().extend(metrics.iter().take(delivered).map( |metric| metric.value.clone() ));
+        return result;
+    }
+}
+
+/// A source whose poll never finishes
+#[cfg(test)]
+struct StuckSource {
+    name : String,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Source for StuckSource {
+    fn name(&self) -> &String {
+        return &self.name;
+    }
+    async fn poll(&mut self) -> Result<Vec<Metric>> {
+        return futures::future::pending().await;
+    }
+}
+
+#[cfg(test)]
+fn test_manager(destination : ScriptedDestination, buffer : Option<DiskBuffer>) -> Manager {
+    return Manager {
+        destinations: vec![Box::new(destination)],
+        buffers: vec![buffer],
+        sources: vec![],
+        intervals: vec![],
+        commands: None,
+        started: Instant::now(),
+    };
+}
+
+#[tokio::test]
+async fn test_rejected_batches_do_not_block_the_queue() {
+    let path = std::env::temp_dir().join(format!("homer_manager_replay_{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut config = BufferConfig::example_config(&path.to_string_lossy());
+    config.retry_initial = 0;
+    let destination = ScriptedDestination {
+        name: "scripted".to_string(),
+        results: vec![
+            // First batch half gets there, then the rest is queued
+            Err(Error::send("timed out".to_string()).with_delivered(1)),
+            // Replaying it is refused outright, so it's dropped rather than blocking the second batch
+            Err(Error::rejected("bad data".to_string())),
+            Ok(()),
+            // A refused batch is never queued
+            Err(Error::rejected("bad data".to_string())),
+        ].into_iter().collect(),
+        received: Arc::new(std::sync::Mutex::new(vec![])),
+    };
+    let received = Arc::clone(&destination.received);
+    let mut manager = test_manager(destination, Some(DiskBuffer::open("scripted", config)));
+
+    manager.send_metrics(&vec![Metric::new("a", "Count", 1), Metric::new("a", "Count", 2)]).await;
+    assert_eq!(manager.buffers[0].as_ref().unwrap().front().unwrap()[0].value, MetricValue::Integer(2));
+    manager.send_metrics(&vec![Metric::new("a", "Count", 3)]).await;
+    manager.send_metrics(&vec![Metric::new("a", "Count", 4)]).await;
+
+    let buffer = manager.buffers[0].as_ref().unwrap();
+    assert!(buffer.is_empty());
+    assert_eq!(buffer.dropped_metrics, 2);
+    assert_eq!(*received.lock().unwrap(), vec![MetricValue::Integer(1), MetricValue::Integer(3)]);
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn test_run_answers_commands() {
+    let destination = ScriptedDestination {
+        name: "scripted".to_string(),
+        results: std::collections::VecDeque::new(),
+        received: Arc::new(std::sync::Mutex::new(vec![])),
+    };
+    let mut manager = test_manager(destination, None);
+    let (command_sender, command_receiver) = tokio::sync::mpsc::unbounded_channel();
+    manager.commands = Some(command_receiver);
+
+    let replies = Arc::new(std::sync::Mutex::new(vec![]));
+    for command in [RelayCommand::Poll, RelayCommand::Scan, RelayCommand::Status, RelayCommand::Reload] {
+        let replies_ref = Arc::clone(&replies);
+        let reply = Box::new( move |answer : String| replies_ref.lock().unwrap().push(answer) );
+        command_sender.send(CommandRequest { command, reply }).unwrap();
+    }
+    // Keep the sender, as a closed Ctrl-C channel counts as a stop
+    let (_ctrl_c_sender, ctrl_c) = crossbeam_channel::bounded(1);
+    assert_eq!(manager.run(ctrl_c).await, RunOutcome::Reload);
+
+    let replies = replies.lock().unwrap().clone();
+    assert_eq!(replies[0], "polled 0 sources");
+    assert_eq!(replies[1], "no sources scan");
+    let status : serde_json::Value = serde_json::from_str(&replies[2]).unwrap();
+    assert_eq!(status["destinations"][0]["name"], "scripted");
+    assert_eq!(replies[3], "reloading configuration");
+
+    let (ctrl_c_sender, ctrl_c) = crossbeam_channel::bounded(1);
+    ctrl_c_sender.send(()).unwrap();
+    assert_eq!(manager.run(ctrl_c).await, RunOutcome::Stopped);
+}
+
+#[tokio::test]
+async fn test_stop_during_poll() {
+    let destination = ScriptedDestination {
+        name: "scripted".to_string(),
+        results: std::collections::VecDeque::new(),
+        received: Arc::new(std::sync::Mutex::new(vec![])),
+    };
+    let mut manager = test_manager(destination, None);
+    manager.sources.push(Box::new(StuckSource { name: "stuck".to_string() }));
+    manager.intervals.push(Duration::from_secs(60));
+
+    let (ctrl_c_sender, ctrl_c) = crossbeam_channel::bounded(1);
+    std::thread::spawn( move || {
+        std::thread::sleep(Duration::from_millis(100));
+        let _ = ctrl_c_sender.send(());
+    });
+    let outcome = tokio::time::timeout(Duration::from_secs(5), manager.run(ctrl_c)).await;
+    assert_eq!(outcome.unwrap(), RunOutcome::Stopped);
+}
@@ -0,0 +1,86 @@
+use std::fmt;
+
+/// Everything that can go wrong in the relay.  Plugins return these rather than panicking,
+/// so the Manager can log the failure and carry on with everything else.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Toml(toml::de::Error),
+    /// Something in the configuration doesn't make sense
+    Config(String),
+    /// Data from a file or device wasn't in the expected format
+    Parse(String),
+    Bluetooth(String),
+    /// A destination couldn't deliver.  retryable is false if sending the same data again won't help, e.g. it was
+    /// refused as invalid.  delivered counts the metrics at the front of the batch that got there before it failed.
+    Send { message : String, retryable : bool, delivered : usize },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error : {}", e),
+            Error::Json(e) => write!(f, "JSON error : {}", e),
+            Error::Toml(e) => write!(f, "TOML error : {}", e),
+            Error::Config(message) => write!(f, "Configuration error : {}", message),
+            Error::Parse(message) => write!(f, "Parse error : {}", message),
+            Error::Bluetooth(message) => write!(f, "Bluetooth error : {}", message),
+            Error::Send { message, retryable: true, .. } => write!(f, "Send error : {}", message),
+            Error::Send { message, retryable: false, .. } => write!(f, "Rejected : {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// A failure that might go away, e.g. the destination is down
+    pub fn send(message : String) -> Error {
+        return Error::Send { message, retryable: true, delivered: 0 };
+    }
+
+    /// The destination refused the data, so there's no point sending it again
+    pub fn rejected(message : String) -> Error {
+        return Error::Send { message, retryable: false, delivered: 0 };
+    }
+
+    /// Note how many metrics from the front of the batch got through before this went wrong
+    pub fn with_delivered(self, delivered : usize) -> Error {
+        let retryable = self.is_retryable();
+        return match self {
+            Error::Send { message, retryable, .. } => Error::Send { message, retryable, delivered },
+            e => Error::Send { message: e.to_string(), retryable, delivered }
+        };
+    }
+
+    /// Whether a batch that failed with this is worth keeping to try again
+    pub fn is_retryable(&self) -> bool {
+        return match self {
+            Error::Send { retryable, .. } => *retryable,
+            Error::Io(_) | Error::Bluetooth(_) => true,
+            Error::Json(_) | Error::Toml(_) | Error::Config(_) | Error::Parse(_) => false,
+        };
+    }
+
+    pub fn delivered(&self) -> usize {
+        return match self {
+            Error::Send { delivered, .. } => *delivered,
+            _ => 0
+        };
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error { Error::Io(e) }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error { Error::Json(e) }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Error { Error::Toml(e) }
+}
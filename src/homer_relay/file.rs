@@ -0,0 +1,344 @@
+//! Keep a local record of every reading as JSON Lines or CSV, rotating by size and/or date
+
+pub use super::core::*;
+
+use serde::{Serialize, Deserialize};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+#[derive(Deserialize,Serialize,Clone,Copy,Debug,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    /// One JSON document per metric
+    Jsonl,
+    /// timestamp,source,object,property,value,unit
+    Csv,
+}
+
+impl Default for FileFormat {
+    fn default() -> FileFormat {
+        return FileFormat::Jsonl;
+    }
+}
+
+#[derive(Deserialize,Serialize,Clone,Copy,Debug,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RotationPeriod {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Default for RotationPeriod {
+    fn default() -> RotationPeriod {
+        return RotationPeriod::Never;
+    }
+}
+
+const CSV_HEADER : &str = "timestamp,source,object,property,value,unit\n";
+
+fn default_keep() -> usize {
+    return 7;
+}
+
+#[derive(Deserialize,Serialize)]
+pub struct DestinationFileConfig {
+    pub path : String,
+    #[serde(default)]
+    pub format : FileFormat,
+    /// Start a new file once the current one would grow past this
+    #[serde(default)]
+    pub max_bytes : Option<u64>,
+    /// Start a new file every hour or day (UTC)
+    #[serde(default)]
+    pub rotate : RotationPeriod,
+    /// Rotated files to keep, the oldest are deleted
+    #[serde(default = "default_keep")]
+    pub keep : usize,
+    /// gzip files as they're rotated
+    #[serde(default)]
+    pub compress : bool,
+}
+
+impl DestinationFileConfig {
+    pub fn example_config()->DestinationFileConfig {
+        return DestinationFileConfig {
+            path: "metrics.jsonl".to_string(),
+            format: FileFormat::Jsonl,
+            max_bytes: Some(10 * 1024 * 1024),
+            rotate: RotationPeriod::Daily,
+            keep: default_keep(),
+            compress: true,
+        }
+    }
+}
+
+pub struct DestinationFile {
+    name : String,
+    config : Box<DestinationFileConfig>,
+    writer : Option<BufWriter<std::fs::File>>,
+    /// Size of the current file
+    bytes : u64,
+    /// Size of the current file before any metrics, i.e. the CSV header
+    header_bytes : u64,
+    /// Which hour or day the current file is for
+    period : String,
+}
+
+#[typetag::serde(name = "file")]
+impl DestinationConfig for DestinationFileConfig {
+    fn name(&self) -> String {
+        return format!("file {}", self.path);
+    }
+    fn init(self : Box<Self>) -> Result<Box<dyn Destination>> {
+        let mut destination = DestinationFile {
+            name: self.name(),
+            config: self,
+            writer: None,
+            bytes: 0,
+            header_bytes: 0,
+            period: String::new(),
+        };
+        // Find out about an unwritable path now rather than on the first report
+        destination.open(Utc::now())?;
+        return Ok(Box::new(destination));
+    }
+}
+
+fn csv_field(value : &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        return format!("\"{}\"", value.replace('"', "\"\""));
+    }
+    return value.to_string();
+}
+
+impl DestinationFile {
+    fn period_key(&self, time : DateTime<Utc>) -> String {
+        return match self.config.rotate {
+            RotationPeriod::Never => String::new(),
+            RotationPeriod::Hourly => time.format("%Y%m%d%H").to_string(),
+            RotationPeriod::Daily => time.format("%Y%m%d").to_string(),
+        };
+    }
+
+    fn open(&mut self, now : DateTime<Utc>) -> Result<()> {
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.config.path)?;
+        let metadata = file.metadata()?;
+        self.bytes = metadata.len();
+        self.header_bytes = 0;
+
+        // Carrying on with a file from a previous run, which may be from an earlier day
+        let started = match metadata.modified() {
+            Ok(modified) if self.bytes > 0 => DateTime::<Utc>::from(modified),
+            _ => now
+        };
+        self.period = self.period_key(started);
+
+        if self.config.format == FileFormat::Csv {
+            if self.bytes == 0 {
+                file.write_all(CSV_HEADER.as_bytes())?;
+                self.bytes = CSV_HEADER.len() as u64;
+            }
+            self.header_bytes = CSV_HEADER.len() as u64;
+        }
+        self.writer = Some(BufWriter::new(file));
+        return Ok(());
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+        }
+        return Ok(());
+    }
+
+    /// Move the current file aside, compress it if asked and start a new one
+    fn rotate(&mut self, now : DateTime<Utc>) -> Result<()> {
+        self.flush()?;
+        self.writer = None;
+
+        let base = format!("{}.{}", self.config.path, now.format("%Y%m%dT%H%M%S"));
+        let mut rotated = base.clone();
+        let mut suffix = 1;
+        while Path::new(&rotated).exists() || Path::new(&format!("{}.gz", rotated)).exists() {
+            rotated = format!("{}-{}", base, suffix);
+            suffix += 1;
+        }
+        std::fs::rename(&self.config.path, &rotated)?;
+        println!("{} - rotated to {}", self.name, rotated);
+
+        if self.config.compress {
+            let mut input = std::fs::File::open(&rotated)?;
+            let output = std::fs::File::create(format!("{}.gz", rotated))?;
+            let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+            std::io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?.sync_all()?;
+            std::fs::remove_file(&rotated)?;
+        }
+
+        self.prune()?;
+        return self.open(now);
+    }
+
+    /// Delete the oldest rotated files beyond the retention count
+    fn prune(&self) -> Result<()> {
+        let path = Path::new(&self.config.path);
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new(".")
+        };
+        let prefix = format!("{}.", path.file_name().map( |name| name.to_string_lossy().to_string() ).unwrap_or_default());
+
+        let mut rotated : Vec<(std::time::SystemTime, String)> = std::fs::read_dir(directory)?
+            .filter_map( |entry| entry.ok() )
+            .filter( |entry| entry.file_name().to_string_lossy().starts_with(&prefix) )
+            .filter_map( |entry| Some((entry.metadata().ok()?.modified().ok()?, entry.file_name().to_string_lossy().to_string())) )
+            .collect();
+        // Names made in the same second don't sort by age, so go by when they were written
+        rotated.sort();
+        while rotated.len() > self.config.keep {
+            let oldest = directory.join(rotated.remove(0).1);
+            log::info!("{} - removing {}", self.name, oldest.display());
+            std::fs::remove_file(oldest)?;
+        }
+        return Ok(());
+    }
+
+    fn record(&self, metric : &Metric) -> Result<String> {
+        return match self.config.format {
+            FileFormat::Jsonl => Ok(serde_json::to_string(metric)?),
+            FileFormat::Csv => {
+                let timestamp = metric.timestamp.map( |t| t.to_rfc3339() ).unwrap_or_default();
+                let fields = [
+                    timestamp,
+                    metric.source.clone().unwrap_or_default(),
+                    metric.object.clone(),
+                    metric.property.clone(),
+                    metric.value.to_string(),
+                    metric.unit.clone().unwrap_or_default(),
+                ];
+                Ok(fields.iter().map( |field| csv_field(field) ).collect::<Vec<String>>().join(","))
+            }
+        };
+    }
+
+    fn write_metric(&mut self, metric : &Metric, now : DateTime<Utc>) -> Result<()> {
+        if self.writer.is_none() {
+            self.open(now)?;
+        }
+        let line = format!("{}\n", self.record(metric)?);
+
+        let new_period = self.config.rotate != RotationPeriod::Never && self.period_key(now) != self.period;
+        let too_big = match self.config.max_bytes {
+            Some(max_bytes) => self.bytes > self.header_bytes && self.bytes + line.len() as u64 > max_bytes,
+            None => false
+        };
+        if new_period || too_big {
+            self.rotate(now)?;
+        }
+
+        if let Some(writer) = &mut self.writer {
+            writer.write_all(line.as_bytes())?;
+        }
+        self.bytes += line.len() as u64;
+        return Ok(());
+    }
+}
+
+#[async_trait]
+impl Destination for DestinationFile {
+    fn name(&self) -> &String {
+        return &self.name;
+    }
+    async fn report(&mut self, metrics: &Vec<Metric>) -> Result<()> {
+        let now = Utc::now();
+        for metric in metrics {
+            self.write_metric(metric, now)?;
+        }
+        return self.flush();
+    }
+    fn shutdown(&mut self) -> Option<std::thread::JoinHandle<()>> {
+        if let Err(e) = self.flush() {
+            log::error!("{} - unable to flush : {}", self.name, e);
+        }
+        self.writer = None;
+        return None;
+    }
+}
+
+
+#[cfg(test)]
+fn test_destination(name : &str, format : FileFormat) -> (std::path::PathBuf, DestinationFile) {
+    let directory = std::env::temp_dir().join(format!("homer_file_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    let config = DestinationFileConfig {
+        path: directory.join("metrics.log").to_string_lossy().to_string(),
+        format,
+        max_bytes: None,
+        rotate: RotationPeriod::Never,
+        keep: 2,
+        compress: false,
+    };
+    let destination = DestinationFile { name: config.name(), config: Box::new(config), writer: None, bytes: 0, header_bytes: 0, period: String::new() };
+    return (directory, destination);
+}
+
+#[cfg(test)]
+fn list_directory(directory : &Path) -> Vec<String> {
+    let mut names : Vec<String> = std::fs::read_dir(directory).unwrap().map( |entry| entry.unwrap().file_name().to_string_lossy().to_string() ).collect();
+    names.sort();
+    return names;
+}
+
+#[test]
+fn test_csv_records() {
+    let (directory, mut destination) = test_destination("csv", FileFormat::Csv);
+    let now = Utc::now();
+    destination.write_metric(&Metric::new("Fish Tank", "Temperature", 24.5).with_unit("°C"), now).unwrap();
+    destination.write_metric(&Metric::new("Remote", "Button", "say \"hi\", twice"), now).unwrap();
+    destination.shutdown();
+
+    let contents = std::fs::read_to_string(directory.join("metrics.log")).unwrap();
+    assert_eq!(contents, concat!(
+        "timestamp,source,object,property,value,unit\n",
+        ",,Fish Tank,Temperature,24.5,°C\n",
+        ",,Remote,Button,\"say \"\"hi\"\", twice\",\n",
+    ));
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_rotation_and_retention() {
+    let (directory, mut destination) = test_destination("rotate", FileFormat::Jsonl);
+    destination.config.max_bytes = Some(150);
+    destination.config.rotate = RotationPeriod::Daily;
+    destination.config.compress = true;
+
+    let day1 = DateTime::parse_from_rfc3339("2021-05-01T23:59:00Z").unwrap().with_timezone(&Utc);
+    let day2 = DateTime::parse_from_rfc3339("2021-05-02T00:01:00Z").unwrap().with_timezone(&Utc);
+    let metric = Metric::new("Shed", "Temperature", 8.5);
+    destination.open(day1).unwrap();
+    destination.write_metric(&metric, day1).unwrap();
+    // The next day starts a new file, even though it's small
+    destination.write_metric(&metric, day2).unwrap();
+    assert_eq!(list_directory(&directory), vec!["metrics.log", "metrics.log.20210502T000100.gz"]);
+
+    // Then size kicks in, with rotations in the same second kept apart and only the newest two kept
+    for _ in 0..4 {
+        destination.write_metric(&metric, day2).unwrap();
+    }
+    destination.shutdown();
+    let files = list_directory(&directory);
+    assert_eq!(files.len(), 3);
+    assert!(files[1..].iter().all( |name| name.starts_with("metrics.log.20210502T000100") && name.ends_with(".gz") ));
+    assert_eq!(std::fs::read_to_string(directory.join("metrics.log")).unwrap().lines().count(), 1);
+
+    let mut rotated = String::new();
+    std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(std::fs::File::open(directory.join(&files[1])).unwrap()), &mut rotated).unwrap();
+    let restored : Metric = serde_json::from_str(rotated.lines().next().unwrap()).unwrap();
+    assert_eq!(restored.value, MetricValue::Float(8.5));
+    std::fs::remove_dir_all(&directory).unwrap();
+}
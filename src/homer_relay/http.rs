@@ -0,0 +1,38 @@
+//! What the destinations that talk HTTP have in common
+
+use super::error::{Error, Result};
+use std::time::Duration;
+
+/// Wait before the first retry, doubling after that.  Kept short as a retry holds up every other destination.
+const RETRY_INITIAL : Duration = Duration::from_millis(250);
+
+/// Send a request, trying again after connection failures, server errors and 429s.  Anything else won't get
+/// better by trying again (bad token, bad data...), so comes back as rejected.
+pub async fn send_with_retry(name : &str, request : reqwest::RequestBuilder, retries : u32) -> Result<()> {
+    let mut delay = RETRY_INITIAL;
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+        let request = request.try_clone().ok_or(Error::Config("request body can't be sent twice".to_string()))?;
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                log::warn!("{} - request failed : {}", name, e);
+                continue;
+            }
+        };
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let message = response.text().await.unwrap_or_default();
+        if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            log::warn!("{} - request failed with {} : {}", name, status, message);
+            continue;
+        }
+        return Err(Error::rejected(format!("{} : {}", status, message)));
+    }
+    return Err(Error::send(format!("gave up after {} attempts", retries + 1)));
+}
@@ -0,0 +1,271 @@
+pub use super::core::*;
+
+use serde::{Serialize, Deserialize};
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+pub const DEFAULT_SYSFS_ROOT : &str = "/sys";
+
+fn default_root() -> String {
+    return DEFAULT_SYSFS_ROOT.to_string();
+}
+
+#[derive(Deserialize,Serialize)]
+pub struct SourceHwmonConfig {
+    #[serde(default = "default_root")]
+    root : String,
+    /// Friendly object names keyed by chip name, thermal zone type or directory, e.g. "cpu_thermal" = "Pi"
+    #[serde(default)]
+    aliases : HashMap<String, String>,
+    #[serde(default)]
+    interval : Option<u64>,
+}
+
+pub struct SourceHwmon {
+    config : Box<SourceHwmonConfig>,
+    name: String,
+}
+
+impl SourceHwmonConfig {
+    pub fn example_config()->SourceHwmonConfig {
+        let mut aliases = HashMap::new();
+        aliases.insert("cpu_thermal".to_string(), "Pi".to_string());
+        return SourceHwmonConfig {
+            root: default_root(),
+            aliases,
+            interval: None
+        }
+    }
+}
+
+/// What a raw hwmon reading of this kind is divided by, and the unit that leaves it in.
+/// See https://www.kernel.org/doc/html/latest/hwmon/sysfs-interface.html
+pub fn hwmon_scale(kind : &str) -> Option<(i64, &'static str)> {
+    return match kind {
+        "temp" => Some((1000, "°C")),
+        "in" => Some((1000, "V")),
+        "curr" => Some((1000, "A")),
+        "fan" => Some((1, "RPM")),
+        "power" => Some((1_000_000, "W")),
+        "energy" => Some((1_000_000, "J")),
+        "humidity" => Some((1000, "%")),
+        "freq" => Some((1, "Hz")),
+        _ => None
+    };
+}
+
+/// Split a file name like temp1_input into its kind and channel, i.e. ("temp", "temp1")
+pub fn parse_input_name(file_name : &str) -> Option<(&str, &str)> {
+    let channel = file_name.strip_suffix("_input")?;
+    let kind = channel.trim_end_matches( |c : char| c.is_ascii_digit() );
+    if kind.is_empty() || kind.len() == channel.len() {
+        return None;
+    }
+    return Some((kind, channel));
+}
+
+fn read_trimmed(path : &Path) -> Option<String> {
+    return std::fs::read_to_string(path).ok().map( |contents| contents.trim().to_string() );
+}
+
+fn read_raw(path : &Path) -> Result<i64, String> {
+    let contents = std::fs::read_to_string(path).map_err( |e| format!("{:?}", e) )?;
+    return contents.trim().parse().map_err( |e| format!("bad reading {} : {:?}", contents.trim(), e) );
+}
+
+/// Directories under a sysfs class starting with prefix, e.g. thermal_zone0, thermal_zone1, ...
+fn class_entries(class_path : &Path, prefix : &str) -> Vec<String> {
+    let mut entries : Vec<String> = match std::fs::read_dir(class_path) {
+        Ok(entries) => entries
+            .filter_map( |entry| entry.ok() )
+            .map( |entry| entry.file_name().to_string_lossy().to_string() )
+            .filter( |name| name.starts_with(prefix) )
+            .collect(),
+        Err(_) => vec![]
+    };
+    entries.sort();
+    return entries;
+}
+
+impl SourceHwmon {
+    fn object(&self, directory : &str, name : &str) -> String {
+        return self.config.aliases.get(directory).or(self.config.aliases.get(name)).cloned().unwrap_or(name.to_string());
+    }
+
+    /// Read each thermal zone, returning their types
+    fn read_thermal_zones(&self, metrics : &mut Vec<Metric>) -> HashSet<String> {
+        let mut zone_types = HashSet::new();
+        let class_path = Path::new(&self.config.root).join("class/thermal");
+        for zone in class_entries(&class_path, "thermal_zone") {
+            let zone_path = class_path.join(&zone);
+            let zone_type = read_trimmed(&zone_path.join("type")).unwrap_or(zone.clone());
+            // The kernel also shows thermal zones as hwmon chips named after the type, with - turned into _
+            zone_types.insert(zone_type.replace('-', "_"));
+            match read_raw(&zone_path.join("temp")) {
+                Ok(millidegrees) => {
+                    let object = self.object(&zone, &zone_type);
+                    metrics.push(Metric::new(&object, "Temperature", millidegrees as f64 / 1000.0).with_unit("°C"));
+                }
+                Err(e) => {
+                    log::warn!("{} - skipping {} : {}", self.name, zone, e);
+                }
+            }
+        }
+        return zone_types;
+    }
+
+    /// Read each hwmon chip other than those already read as thermal zones
+    fn read_hwmon_chips(&self, zone_types : &HashSet<String>, metrics : &mut Vec<Metric>) {
+        let class_path = Path::new(&self.config.root).join("class/hwmon");
+        let chips : Vec<(String, String)> = class_entries(&class_path, "hwmon").into_iter()
+            .map( |chip| (read_trimmed(&class_path.join(&chip).join("name")).unwrap_or(chip.clone()), chip) )
+            .collect();
+        for (chip_name, chip) in &chips {
+            if zone_types.contains(chip_name) {
+                log::debug!("{} - {} is thermal zone {}", self.name, chip, chip_name);
+                continue;
+            }
+            let chip_path = class_path.join(chip);
+            // Several chips can share a name, e.g. one per NVMe drive, so tell those apart by directory
+            let object = if chips.iter().filter( |(name, _)| name == chip_name ).count() > 1 {
+                self.object(chip, &format!("{} {}", chip_name, chip))
+            } else {
+                self.object(chip, chip_name)
+            };
+
+            let mut inputs : Vec<String> = match std::fs::read_dir(&chip_path) {
+                Ok(entries) => entries
+                    .filter_map( |entry| entry.ok() )
+                    .map( |entry| entry.file_name().to_string_lossy().to_string() )
+                    .filter( |name| name.ends_with("_input") )
+                    .collect(),
+                Err(e) => {
+                    log::warn!("{} - skipping {} : {:?}", self.name, chip, e);
+                    continue;
+                }
+            };
+            inputs.sort();
+
+            for input in inputs {
+                let (kind, channel) = match parse_input_name(&input) {
+                    Some(parsed) => parsed,
+                    None => continue
+                };
+                let (divisor, unit) = match hwmon_scale(kind) {
+                    Some(scale) => scale,
+                    None => {
+                        log::debug!("{} - don't know how to scale {}/{}", self.name, chip, input);
+                        continue;
+                    }
+                };
+                let property = read_trimmed(&chip_path.join(format!("{}_label", channel))).unwrap_or(channel.to_string());
+                match read_raw(&chip_path.join(&input)) {
+                    Ok(raw) if divisor == 1 => metrics.push(Metric::new(&object, &property, raw).with_unit(unit)),
+                    Ok(raw) => metrics.push(Metric::new(&object, &property, raw as f64 / divisor as f64).with_unit(unit)),
+                    Err(e) => {
+                        // Sensors that are switched off give EIO / ENODATA rather than a value
+                        log::warn!("{} - skipping {}/{} : {}", self.name, chip, input, e);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn read_sensors(&self) -> Result<Vec<Metric>> {
+        if !Path::new(&self.config.root).join("class").is_dir() {
+            return Err(Error::Config(format!("no sysfs classes under {}", self.config.root)));
+        }
+        let mut metrics = vec![];
+        let zone_types = self.read_thermal_zones(&mut metrics);
+        self.read_hwmon_chips(&zone_types, &mut metrics);
+        return Ok(metrics);
+    }
+}
+
+#[typetag::serde(name = "hwmon")]
+impl SourceConfig for SourceHwmonConfig {
+    fn name(&self) -> String {
+        return format!("hwmon {}", self.root);
+    }
+    fn interval(&self) -> Option<u64> {
+        return self.interval;
+    }
+    fn init(self : Box<Self>) -> Result<Box<dyn Source>> {
+        return Ok(Box::new( SourceHwmon{
+            name: self.name(),
+            config: self
+        } ))
+    }
+}
+
+#[async_trait]
+impl Source for SourceHwmon {
+    fn name(&self) -> &String {
+        return &self.name;
+    }
+    async fn poll(&mut self) -> Result<Vec<Metric>> {
+        return self.read_sensors();
+    }
+}
+
+
+#[test]
+fn test_parse_input_name() {
+    assert_eq!(parse_input_name("temp1_input"), Some(("temp", "temp1")));
+    assert_eq!(parse_input_name("in10_input"), Some(("in", "in10")));
+    assert_eq!(parse_input_name("temp1_label"), None);
+    assert_eq!(parse_input_name("temp_input"), None);
+    assert_eq!(hwmon_scale("in"), Some((1000, "V")));
+    assert_eq!(hwmon_scale("pwm"), None);
+}
+
+#[test]
+fn test_read_fake_sysfs() {
+    let root = std::env::temp_dir().join(format!("homer_hwmon_{}", std::process::id()));
+    let write = |path : &str, contents : &str| {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    };
+    write("class/thermal/thermal_zone0/type", "cpu_thermal\n");
+    write("class/thermal/thermal_zone0/temp", "48312\n");
+    write("class/thermal/thermal_zone1/type", "gpu_thermal\n");
+    write("class/thermal/thermal_zone1/temp", "\n");
+    write("class/hwmon/hwmon0/name", "nct6775\n");
+    write("class/hwmon/hwmon0/temp1_input", "35500\n");
+    write("class/hwmon/hwmon0/temp1_label", "SYSTIN\n");
+    write("class/hwmon/hwmon0/in0_input", "1224\n");
+    write("class/hwmon/hwmon0/fan2_input", "1150\n");
+    write("class/hwmon/hwmon0/pwm2", "128\n");
+    write("class/hwmon/hwmon1/name", "cpu_thermal\n");
+    write("class/hwmon/hwmon1/temp1_input", "48312\n");
+    write("class/hwmon/hwmon2/name", "nvme\n");
+    write("class/hwmon/hwmon2/temp1_input", "41850\n");
+    write("class/hwmon/hwmon3/name", "nvme\n");
+    write("class/hwmon/hwmon3/temp1_input", "39850\n");
+
+    let mut aliases = HashMap::new();
+    aliases.insert("cpu_thermal".to_string(), "Pi".to_string());
+    let config = Box::new(SourceHwmonConfig {
+        root: root.to_string_lossy().to_string(),
+        aliases,
+        interval: None
+    });
+    let source = SourceHwmon { name: config.name(), config };
+    let metrics = source.read_sensors().unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    let readings : Vec<(String, String, String, Option<String>)> = metrics.iter().map( |m| (m.object.clone(), m.property.clone(), m.value.to_string(), m.unit.clone()) ).collect();
+    let expected = [
+        ("Pi", "Temperature", "48.312", "°C"),
+        ("nct6775", "fan2", "1150", "RPM"),
+        ("nct6775", "in0", "1.224", "V"),
+        ("nct6775", "SYSTIN", "35.5", "°C"),
+        ("nvme hwmon2", "temp1", "41.85", "°C"),
+        ("nvme hwmon3", "temp1", "39.85", "°C"),
+    ];
+    assert_eq!(readings, expected.iter().map( |(o, p, v, u)| (o.to_string(), p.to_string(), v.to_string(), Some(u.to_string())) ).collect::<Vec<_>>());
+
+    assert!(source.read_sensors().is_err());
+}
@@ -0,0 +1,221 @@
+//! Write to InfluxDB 2 in line protocol, see https://docs.influxdata.com/influxdb/v2/reference/syntax/line-protocol/
+
+pub use super::core::*;
+use super::buffer::BufferConfig;
+use super::http::send_with_retry;
+
+use serde::{Serialize, Deserialize};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::Duration;
+
+fn default_url() -> String {
+    return "http://localhost:8086".to_string();
+}
+
+fn default_gzip() -> bool {
+    return true;
+}
+
+fn default_retries() -> u32 {
+    return 2;
+}
+
+fn default_timeout() -> u64 {
+    return 10;
+}
+
+#[derive(Deserialize,Serialize)]
+pub struct DestinationInfluxDBConfig {
+    #[serde(default = "default_url")]
+    pub url : String,
+    pub org : String,
+    pub bucket : String,
+    /// API token, sent as "Authorization: Token ..."
+    #[serde(default)]
+    pub token : Option<String>,
+    /// Extra tags on every point, e.g. site = "home"
+    #[serde(default)]
+    pub tags : BTreeMap<String, String>,
+    #[serde(default = "default_gzip")]
+    pub gzip : bool,
+    /// Further attempts after a failed write before giving up on the batch.  These hold up every other
+    /// destination, so keep them few and leave longer outages to the buffer.
+    #[serde(default = "default_retries")]
+    pub retries : u32,
+    /// Seconds to wait for a response
+    #[serde(default = "default_timeout")]
+    pub timeout : u64,
+    /// Keep metrics on disk while InfluxDB is unreachable
+    #[serde(default)]
+    pub buffer : Option<BufferConfig>,
+}
+
+impl DestinationInfluxDBConfig {
+    pub fn example_config()->DestinationInfluxDBConfig {
+        return DestinationInfluxDBConfig {
+            url: default_url(),
+            org: "home".to_string(),
+            bucket: "homer".to_string(),
+            token: Some("changeme".to_string()),
+            tags: BTreeMap::new(),
+            gzip: default_gzip(),
+            retries: default_retries(),
+            timeout: default_timeout(),
+            buffer: None,
+        }
+    }
+}
+
+pub struct DestinationInfluxDB {
+    name : String,
+    config : Box<DestinationInfluxDBConfig>,
+    client : reqwest::Client,
+}
+
+#[typetag::serde(name = "influxdb")]
+impl DestinationConfig for DestinationInfluxDBConfig {
+    fn name(&self) -> String {
+        return format!("influxdb {}/{}", self.org, self.bucket);
+    }
+    fn buffer(&self) -> Option<BufferConfig> {
+        return self.buffer.clone();
+    }
+    fn init(self : Box<Self>) -> Result<Box<dyn Destination>> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(self.timeout))
+            .build()
+            .map_err( |e| Error::Config(format!("HTTP client : {}", e)) )?;
+        return Ok(Box::new( DestinationInfluxDB{
+            name: self.name(),
+            config: self,
+            client
+        } ))
+    }
+}
+
+/// Measurement names can't have unescaped commas or spaces
+fn escape_measurement(value : &str) -> String {
+    return value.replace('\\', "\\\\").replace(',', "\\,").replace(' ', "\\ ");
+}
+
+/// Tag keys, tag values and field keys also can't have unescaped equals signs
+fn escape_key(value : &str) -> String {
+    return escape_measurement(value).replace('=', "\\=");
+}
+
+/// Numbers always go as floats.  A field's type is fixed by the first point written, and the same property can be
+/// an integer from one sensor and a float from another, which InfluxDB refuses the whole batch for.
+fn field_value(value : &MetricValue) -> String {
+    return match value {
+        MetricValue::Boolean(b) => b.to_string(),
+        MetricValue::Integer(i) => (*i as f64).to_string(),
+        MetricValue::Float(f) => f.to_string(),
+        MetricValue::Text(s) => format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"")),
+    };
+}
+
+/// One line per metric: <property>,object=<object>[,tags] value=<value> [<nanoseconds>]
+pub fn line_protocol(metric : &Metric, tags : &BTreeMap<String, String>) -> String {
+    let mut all_tags = tags.clone();
+    all_tags.insert("object".to_string(), metric.object.clone());
+
+    let mut line = escape_measurement(&metric.property);
+    for (key, value) in &all_tags {
+        // Empty tag values aren't allowed
+        if !value.is_empty() {
+            line.push_str(&format!(",{}={}", escape_key(key), escape_key(value)));
+        }
+    }
+    line.push_str(&format!(" value={}", field_value(&metric.value)));
+    // Outside roughly 1677-2262 there's no nanosecond timestamp, so let InfluxDB use the time it arrives
+    if let Some(nanos) = metric.timestamp.and_then( |timestamp| timestamp.timestamp_nanos_opt() ) {
+        line.push_str(&format!(" {}", nanos));
+    }
+    return line;
+}
+
+fn gzip(body : &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(body)?;
+    return Ok(encoder.finish()?);
+}
+
+impl DestinationInfluxDB {
+    fn request(&self, body : &[u8]) -> Result<reqwest::RequestBuilder> {
+        let url = format!("{}/api/v2/write", self.config.url.trim_end_matches('/'));
+        let mut request = self.client.post(&url)
+            .query(&[("org", self.config.org.as_str()), ("bucket", self.config.bucket.as_str()), ("precision", "ns")])
+            .header("Content-Type", "text/plain; charset=utf-8");
+        if let Some(token) = &self.config.token {
+            request = request.header("Authorization", format!("Token {}", token));
+        }
+        return Ok(if self.config.gzip {
+            request.header("Content-Encoding", "gzip").body(gzip(body)?)
+        } else {
+            request.body(body.to_vec())
+        });
+    }
+}
+
+#[async_trait]
+impl Destination for DestinationInfluxDB {
+    fn name(&self) -> &String {
+        return &self.name;
+    }
+    async fn report(&mut self, metrics: &Vec<Metric>) -> Result<()> {
+        if metrics.is_empty() {
+            return Ok(());
+        }
+        let lines : Vec<String> = metrics.iter().map( |metric| line_protocol(metric, &self.config.tags) ).collect();
+        let body = lines.join("\n");
+        send_with_retry(&self.name, self.request(body.as_bytes())?, self.config.retries).await?;
+        println!("{} - wrote {} points", self.name, lines.len());
+        return Ok(());
+    }
+}
+
+
+#[test]
+fn test_line_protocol() {
+    let timestamp = chrono::DateTime::parse_from_rfc3339("2021-05-01T12:00:00.5Z").unwrap().with_timezone(&chrono::Utc);
+    let mut tags = BTreeMap::new();
+    tags.insert("site".to_string(), "home".to_string());
+
+    let metric = Metric::new("Fish Tank", "Temperature", 24.5).with_timestamp(timestamp);
+    assert_eq!(line_protocol(&metric, &tags), "Temperature,object=Fish\\ Tank,site=home value=24.5 1619870400500000000");
+    assert_eq!(line_protocol(&Metric::new("a=b,c", "Count", 3), &BTreeMap::new()), "Count,object=a\\=b\\,c value=3");
+    assert_eq!(line_protocol(&Metric::new("Door", "Open", true), &BTreeMap::new()), "Open,object=Door value=true");
+    assert_eq!(line_protocol(&Metric::new("Remote", "Button", "say \"hi\""), &BTreeMap::new()), "Button,object=Remote value=\"say \\\"hi\\\"\"");
+}
+
+#[tokio::test]
+async fn test_write_with_retry() {
+    use std::io::Read;
+    let (url, server) = super::test_support::stub_http_server(vec![503, 204]).await;
+    let mut config = DestinationInfluxDBConfig::example_config();
+    config.url = url;
+    let mut destination = Box::new(config).init().unwrap();
+    destination.report(&vec![Metric::new("Shed", "Temperature", 8.5), Metric::new("Shed", "Humidity", 80)]).await.unwrap();
+
+    let requests = server.await.unwrap();
+    assert_eq!(requests.len(), 2);
+    let (head, body) = &requests[1];
+    assert!(head.starts_with("POST /api/v2/write?org=home&bucket=homer&precision=ns "));
+    assert!(head.contains("authorization: Token changeme") || head.contains("Authorization: Token changeme"));
+    let mut lines = String::new();
+    flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut lines).unwrap();
+    assert_eq!(lines, "Temperature,object=Shed value=8.5\nHumidity,object=Shed value=80");
+}
+
+#[tokio::test]
+async fn test_write_rejected() {
+    let (url, server) = super::test_support::stub_http_server(vec![422]).await;
+    let mut config = DestinationInfluxDBConfig::example_config();
+    config.url = url;
+    let mut destination = Box::new(config).init().unwrap();
+    let error = destination.report(&vec![Metric::new("Shed", "Temperature", 8.5)]).await.unwrap_err();
+    assert!(!error.is_retryable());
+    assert_eq!(server.await.unwrap().len(), 1);
+}
@@ -15,10 +15,10 @@ impl DestinationConfig for DestinationLogConfig {
     fn name(&self) -> String {
         return String::from("MetricDestinationLog");
     }
-    fn init(self : Box<Self>) -> Box<dyn Destination> {
-        return Box::new( DestinationLog{
+    fn init(self : Box<Self>) -> Result<Box<dyn Destination>> {
+        return Ok(Box::new( DestinationLog{
             name: "log".to_string()
-        } )
+        } ))
     }
 }
 
@@ -27,10 +27,14 @@ impl Destination for DestinationLog {
     fn name(&self) -> &String { 
         return &self.name;
     }
-    async fn report(&mut self, metrics: &Vec<Metric>) {
+    async fn report(&mut self, metrics: &Vec<Metric>) -> Result<()> {
         for metric in metrics {
-            println!("{} - object {} has a {} of {}", self.name(), metric.object, metric.property, metric.value);
+            let unit = match &metric.unit { Some(unit) => unit.as_str(), None => "" };
+            let timestamp = match &metric.timestamp { Some(timestamp) => timestamp.to_rfc3339(), None => "unknown time".to_string() };
+            let source = match &metric.source { Some(source) => source.as_str(), None => "unknown source" };
+            println!("{} - object {} has a {} of {}{} at {} from {}", self.name(), metric.object, metric.property, metric.value, unit, timestamp, source);
         }
+        return Ok(());
     }
 }
 
@@ -1,11 +1,20 @@
-use rumqttc::{MqttOptions, Client, QoS, Event, Packet, Outgoing};
+pub mod discovery;
+pub mod commands;
+pub mod source;
+
+use rumqttc::{MqttOptions, Client, ClientError, QoS, Event, Packet, Outgoing, Key, LastWill};
 use std::time::Duration;
 use std::thread;
-use std::str;
 use async_trait::async_trait;
 use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub use super::core::*;
+use super::buffer::BufferConfig;
+use discovery::DiscoveryConfig;
+use commands::CommandConfig;
 
 #[derive(Deserialize,Serialize)]
 pub struct DestinationMQTTConfig {
@@ -15,13 +24,239 @@ pub struct DestinationMQTTConfig {
     #[serde(default)]
     pub agent_name: String,
     pub publish_channel: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// PEM file for the broker's CA, turns on TLS
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    /// PEM client certificate and key for mutual TLS
+    #[serde(default)]
+    pub client_cert_file: Option<String>,
+    #[serde(default)]
+    pub client_key_file: Option<String>,
+    /// Seconds
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: u16,
+    #[serde(default = "default_clean_session")]
+    pub clean_session: bool,
+    /// 0, 1 or 2
+    #[serde(default = "default_qos")]
+    pub qos: u8,
+    #[serde(default)]
+    pub payload: PayloadMode,
+    /// Retain and QoS for state messages in each payload mode
+    #[serde(default)]
+    pub publish: PublishModes,
+    /// Retained online/offline availability, defaults to <publish_channel>status
+    #[serde(default)]
+    pub status_topic: Option<String>,
+    /// Home Assistant discovery, on unless enabled = false
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    /// Remote control, off unless some commands are allowed
+    #[serde(default)]
+    pub commands: CommandConfig,
+    /// Keep metrics on disk while the broker is unreachable
+    #[serde(default)]
+    pub buffer: Option<BufferConfig>,
+}
+
+#[derive(Deserialize,Serialize,Clone,Copy,PartialEq,Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadMode {
+    /// Just the value as text
+    Raw,
+    /// JSON document with the value, unit, timestamp and source
+    Json,
+    /// One JSON document per object on <publish_channel><object>, with a field per property
+    Object,
+}
+
+impl Default for PayloadMode {
+    fn default() -> PayloadMode {
+        return PayloadMode::Raw;
+    }
+}
+
+#[derive(Deserialize,Serialize,Clone,Default)]
+pub struct PublishConfig {
+    /// By default only object documents are retained
+    #[serde(default)]
+    pub retain: Option<bool>,
+    /// 0, 1 or 2, the destination's qos if not set
+    #[serde(default)]
+    pub qos: Option<u8>,
+}
 
+#[derive(Deserialize,Serialize,Clone,Default)]
+pub struct PublishModes {
+    #[serde(default)]
+    pub raw: PublishConfig,
+    #[serde(default)]
+    pub json: PublishConfig,
+    #[serde(default)]
+    pub object: PublishConfig,
+}
+
+impl PublishModes {
+    pub fn get(&self, mode : PayloadMode) -> &PublishConfig {
+        return match mode {
+            PayloadMode::Raw => &self.raw,
+            PayloadMode::Json => &self.json,
+            PayloadMode::Object => &self.object,
+        };
+    }
+}
+
+pub const STATUS_ONLINE : &str = "online";
+pub const STATUS_OFFLINE : &str = "offline";
+
+fn default_keep_alive() -> u16 {
+    return 5;
+}
+
+fn default_clean_session() -> bool {
+    return true;
+}
+
+fn default_qos() -> u8 {
+    return 1;
+}
+
+/// Requests queued for the poller thread before we start waiting on it
+const REQUEST_CAPACITY : usize = 100;
+
+pub fn qos_from_level(level : u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        _ => {
+            log::warn!("Unknown MQTT QoS {}, using 1", level);
+            QoS::AtLeastOnce
+        }
+    }
+}
+
+fn read_pem(filename : &str) -> Result<Vec<u8>> {
+    return std::fs::read(filename).map_err( |e| Error::Config(format!("unable to read {} : {}", filename, e)) );
 }
 
 impl DestinationMQTTConfig {
     pub fn example_config()->DestinationMQTTConfig {
-        return DestinationMQTTConfig {server:"localhost".to_string(),port:1883, agent_name:"MessageRelayAgent".to_string(), publish_channel: "/MetricRelay/".to_string()}
+        return DestinationMQTTConfig {
+            server:"localhost".to_string(),
+            port:1883,
+            agent_name:"MessageRelayAgent".to_string(),
+            publish_channel: "/MetricRelay/".to_string(),
+            username: None,
+            password: None,
+            ca_file: None,
+            client_cert_file: None,
+            client_key_file: None,
+            keep_alive: default_keep_alive(),
+            clean_session: default_clean_session(),
+            qos: default_qos(),
+            payload: PayloadMode::Raw,
+            publish: PublishModes::default(),
+            status_topic: None,
+            discovery: DiscoveryConfig::default(),
+            commands: CommandConfig { allow: vec!["poll".to_string(), "status".to_string()], topic: None, reply_topic: None },
+            buffer: None
+        }
+    }
+
+    pub fn status_topic(&self) -> String {
+        match &self.status_topic {
+            Some(topic) => topic.clone(),
+            None => format!("{}status", self.publish_channel)
+        }
+    }
+
+    /// Object documents are a snapshot of the latest state, so are retained unless told otherwise
+    pub fn retain(&self) -> bool {
+        match self.publish.get(self.payload).retain {
+            Some(retain) => retain,
+            None => self.payload == PayloadMode::Object
+        }
     }
+
+    pub fn state_qos(&self) -> QoS {
+        return qos_from_level(self.publish.get(self.payload).qos.unwrap_or(self.qos));
+    }
+
+    pub fn mqtt_options(&self) -> Result<MqttOptions> {
+        let mut mqttoptions = MqttOptions::new( self.agent_name.clone(), self.server.clone(), self.port);
+        mqttoptions.set_keep_alive(self.keep_alive);
+        mqttoptions.set_clean_session(self.clean_session);
+        // The broker tells everyone we've gone if we drop off without saying goodbye
+        mqttoptions.set_last_will(LastWill::new(self.status_topic(), STATUS_OFFLINE, qos_from_level(self.qos), true));
+
+        if let Some(username) = &self.username {
+            let password = self.password.clone().unwrap_or_default();
+            mqttoptions.set_credentials(username.clone(), password);
+        }
+
+        set_tls(&mut mqttoptions, &self.ca_file, &self.client_cert_file, &self.client_key_file)?;
+        return Ok(mqttoptions);
+    }
+}
+
+/// Turn on TLS if there's a CA file, with a client certificate if there's one of those too
+pub fn set_tls(mqttoptions : &mut MqttOptions, ca_file : &Option<String>, client_cert_file : &Option<String>, client_key_file : &Option<String>) -> Result<()> {
+    if let Some(ca_file) = ca_file {
+        mqttoptions.set_ca(read_pem(ca_file)?);
+    }
+
+    match (client_cert_file, client_key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let key = read_pem(key_file)?;
+            let key = if String::from_utf8_lossy(&key).contains("BEGIN EC PRIVATE KEY") {
+                Key::ECC(key)
+            } else {
+                Key::RSA(key)
+            };
+            mqttoptions.set_client_auth(read_pem(cert_file)?, key);
+        }
+        (None, None) => (),
+        _ => {
+            return Err(Error::Config("client_cert_file and client_key_file must be set together".to_string()));
+        }
+    }
+    return Ok(());
+}
+
+pub fn metric_json(metric : &Metric) -> serde_json::Value {
+    return serde_json::json!({
+        "value": metric.value,
+        "unit": metric.unit,
+        "timestamp": metric.timestamp,
+        "source": metric.source
+    });
+}
+
+/// Merge metrics into the latest state of each object, {"values": {"Temperature": 21.5, ...}, "timestamp": ...},
+/// returning the documents for the objects they touched.  Sources poll on their own schedules, so a batch often
+/// has only some of an object's properties, and a retained document without the rest would lose them.
+pub fn object_documents(objects : &mut BTreeMap<String, serde_json::Value>, metrics : &Vec<Metric>) -> BTreeMap<String, serde_json::Value> {
+    let mut touched = HashSet::new();
+    for metric in metrics {
+        let document = objects.entry(metric.object.clone()).or_insert_with( || serde_json::json!({"values": {}}) );
+        document["values"][&metric.property] = serde_json::json!(metric.value);
+        if let Some(timestamp) = metric.timestamp {
+            let newer = match document["timestamp"].as_str() {
+                Some(existing) => existing < timestamp.to_rfc3339().as_str(),
+                None => true
+            };
+            if newer {
+                document["timestamp"] = serde_json::json!(timestamp.to_rfc3339());
+            }
+        }
+        touched.insert(metric.object.clone());
+    }
+    return objects.iter().filter( |(object, _)| touched.contains(*object) ).map( |(object, document)| (object.clone(), document.clone()) ).collect();
 }
 
 #[typetag::serde(name = "mqtt")]
@@ -30,16 +265,29 @@ impl DestinationConfig for DestinationMQTTConfig {
         return String::from("MetricDestinationMQTT");
     }
 
-    fn init(self : Box<Self> ) -> Box<dyn Destination> {
+    fn buffer(&self) -> Option<BufferConfig> {
+        return self.buffer.clone();
+    }
 
-        let mut mqttoptions = MqttOptions::new( self.agent_name.clone(), self.server.clone(), self.port);
-        mqttoptions.set_keep_alive(5);
+    fn init(self : Box<Self> ) -> Result<Box<dyn Destination>> {
 
-        let (client, mut connection) = Client::new(mqttoptions, 10);
+        let mqttoptions = self.mqtt_options()?;
+
+        let (client, mut connection) = Client::new(mqttoptions, REQUEST_CAPACITY);
 
 
         let name = format!("mqtt {}:{}", self.server, self.port);
         let n = name.clone();
+        let mut birth_client = client.clone();
+        let status_topic = self.status_topic();
+        let qos = qos_from_level(self.qos);
+        let connected = Arc::new(AtomicBool::new(false));
+        let connected_flag = Arc::clone(&connected);
+        let command_sender : Arc<Mutex<Option<CommandSender>>> = Arc::new(Mutex::new(None));
+        let command_sender_ref = Arc::clone(&command_sender);
+        let command_config = self.commands.clone();
+        let command_topic = format!("{}/#", self.commands.command_topic(&self.publish_channel));
+        let publish_channel = self.publish_channel.clone();
 
         //The connection belongs to the thread...
         let poller = thread::spawn( move || {
@@ -51,12 +299,64 @@ impl DestinationConfig for DestinationMQTTConfig {
                             Event::Incoming(incoming_msg) => {
                                 match &incoming_msg {
                                      Packet::Publish(packet) => {
-                                        let msg_string = str::from_utf8(&packet.payload).unwrap();
+                                        let msg_string = String::from_utf8_lossy(&packet.payload);
                                         println!("{} : Packet = {}", n, msg_string);
+                                        if let Some(command) = command_config.parse(&publish_channel, &packet.topic) {
+                                            if packet.retain {
+                                                // A retained command would run again on every reconnect, and a reload reconnects
+                                                println!("{} Ignoring retained command {}", n, packet.topic);
+                                                continue;
+                                            }
+                                            let command_name = packet.topic.rsplit('/').next().unwrap_or("").to_string();
+                                            let reply_topic = command_config.reply_topic(&publish_channel, &command_name);
+                                            let request = packet.payload.to_vec();
+                                            let sender = command_sender_ref.lock().unwrap().clone();
+                                            match (command, sender) {
+                                                (Ok(command), Some(sender)) => {
+                                                    let mut reply_client = birth_client.clone();
+                                                    let reply_name = n.clone();
+                                                    let reply = Box::new( move |answer : String| {
+                                                        let document = commands::reply_document(&command_name, true, &answer, &request);
+                                                        if let Err(e) = reply_client.try_publish(reply_topic, qos, false, document.to_string()) {
+                                                            println!("{} Unable to publish reply : {:?}", reply_name, e);
+                                                        }
+                                                    });
+                                                    if sender.send(CommandRequest { command, reply }).is_err() {
+                                                        println!("{} Relay isn't taking commands", n);
+                                                    }
+                                                }
+                                                (result, _) => {
+                                                    let reason = match result {
+                                                        Err(reason) => reason,
+                                                        Ok(_) => "relay isn't taking commands".to_string()
+                                                    };
+                                                    println!("{} Rejecting command {} : {}", n, packet.topic, reason);
+                                                    let document = commands::reply_document(&command_name, false, &reason, &request);
+                                                    if let Err(e) = birth_client.try_publish(reply_topic, qos, false, document.to_string()) {
+                                                        println!("{} Unable to publish reply : {:?}", n, e);
+                                                    }
+                                                }
+                                            }
+                                        }
                                     }
                                     Packet::PubAck(_publish_message) => {
                                         println!("{} Published",n);
                                     }
+                                    Packet::ConnAck(_connack) => {
+                                        connected_flag.store(true, Ordering::SeqCst);
+                                        // Sent on every (re)connect, as the will may have fired in between.
+                                        // try_publish, as a blocking publish here could wait on ourselves.
+                                        println!("{} Connected, publishing {} to {}", n, STATUS_ONLINE, status_topic);
+                                        if let Err(e) = birth_client.try_publish(status_topic.clone(), qos, true, STATUS_ONLINE) {
+                                            println!("{} Unable to publish status : {:?}", n, e);
+                                        }
+                                        if command_config.enabled() {
+                                            println!("{} Listening for commands on {}", n, command_topic);
+                                            if let Err(e) = birth_client.try_subscribe(command_topic.clone(), qos) {
+                                                println!("{} Unable to subscribe to commands : {:?}", n, e);
+                                            }
+                                        }
+                                    }
                                     _ => {
                                         println!("{} In:{:?}", n, incoming_msg);
                                     }
@@ -78,6 +378,7 @@ impl DestinationConfig for DestinationMQTTConfig {
                         }
                     }
                     Err(e) => {
+                        connected_flag.store(false, Ordering::SeqCst);
                         println!("{} Error:{:?}", n, e);
                     }
                 }
@@ -85,12 +386,16 @@ impl DestinationConfig for DestinationMQTTConfig {
             println!("{} Poll thread exiting", n );
         });    
 
-        return Box::new( DestinationMQTT{
+        return Ok(Box::new( DestinationMQTT{
             config : self,
             name,
             client,
-            poller: Some(poller)
-        } )
+            poller: Some(poller),
+            connected,
+            announced: HashSet::new(),
+            objects: BTreeMap::new(),
+            command_sender
+        } ))
 
     }
 }
@@ -101,7 +406,69 @@ pub struct DestinationMQTT {
     config : Box<DestinationMQTTConfig>,
     client : Client,
     poller : Option<std::thread::JoinHandle<()>>,
+    /// Set by the poller thread while we have a session with the broker
+    connected : Arc<AtomicBool>,
+    /// object/property pairs we've sent a discovery document for
+    announced : HashSet<(String, String)>,
+    /// Everything we've published for each object in object mode
+    objects : BTreeMap<String, serde_json::Value>,
+    /// Where the poller thread passes on commands, once the Manager has given us somewhere
+    command_sender : Arc<Mutex<Option<CommandSender>>>,
+}
+
+impl DestinationMQTT {
+    fn node_id(&self) -> &str {
+        match &self.config.discovery.node_id {
+            Some(node_id) => node_id,
+            None if !self.config.agent_name.is_empty() => &self.config.agent_name,
+            None => "homer_rust"
+        }
+    }
+
+    /// Give a (re)connect in progress a moment to finish before we call the broker unreachable
+    async fn wait_for_connection(&self) -> bool {
+        for _ in 0..20 {
+            if self.connected.load(Ordering::SeqCst) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        return self.connected.load(Ordering::SeqCst);
+    }
 
+    /// Queue a message for the poller thread without blocking the runtime, waiting a while if the queue's full
+    async fn publish(&mut self, topic : &str, qos : QoS, retain : bool, payload : &str) -> Result<()> {
+        for _ in 0..500 {
+            match self.client.try_publish(topic, qos, retain, payload.as_bytes().to_vec()) {
+                Ok(()) => return Ok(()),
+                Err(ClientError::TryRequest(e)) if e.is_full() => tokio::time::sleep(Duration::from_millis(10)).await,
+                Err(e) => return Err(Error::send(format!("{:?}", e)))
+            }
+        }
+        return Err(Error::send("request queue full".to_string()));
+    }
+
+    /// Tell Home Assistant about a sensor the first time we see it
+    async fn announce(&mut self, metric : &Metric, state_topic : &str) -> Result<()> {
+        let key = (metric.object.clone(), metric.property.clone());
+        if !self.config.discovery.enabled || self.announced.contains(&key) {
+            return Ok(());
+        }
+        // Templates turn JSON true/false into Python's True/False, so lower them to match payload_on/off
+        let filter = match metric.value { MetricValue::Boolean(_) => " | lower", _ => "" };
+        let value_template = match self.config.payload {
+            PayloadMode::Raw => None,
+            PayloadMode::Json => Some(format!("{{{{ value_json.value{} }}}}", filter)),
+            PayloadMode::Object => Some(format!("{{{{ value_json['values']['{}']{} }}}}", metric.property.replace('\'', "\\'"), filter)),
+        };
+        let topic = self.config.discovery.config_topic(self.node_id(), metric);
+        let status_topic = self.config.status_topic();
+        let document = self.config.discovery.config_document(self.node_id(), state_topic, &status_topic, metric, value_template.as_deref());
+        println!("{} announce {} : {}", self.name(), topic, document);
+        self.publish(&topic, qos_from_level(self.config.qos), true, &document.to_string()).await?;
+        self.announced.insert(key);
+        return Ok(());
+    }
 }
 
 #[async_trait]
@@ -110,21 +477,108 @@ impl Destination for DestinationMQTT {
         return &self.name;
     }
 
-    async fn report(&mut self, metrics: &Vec<Metric>) {
-        for metric in metrics {
-            let channel = format!("{}{}/{}",&self.config.publish_channel, &metric.object, &metric.property);
-            println!("{} publish {} : {}", self.name(), channel, metric.value);
-            let data = metric.value.as_bytes();
-            self.client.publish(channel, QoS::AtLeastOnce, false, data).unwrap();
-            thread::sleep(Duration::from_millis(100));
+    fn accept_commands(&mut self, commands : CommandSender) {
+        *self.command_sender.lock().unwrap() = Some(commands);
+    }
+
+    async fn report(&mut self, metrics: &Vec<Metric>) -> Result<()> {
+        if !self.wait_for_connection().await {
+            return Err(Error::send("not connected to broker".to_string()));
+        }
+        let qos = self.config.state_qos();
+        let retain = self.config.retain();
 
+        if self.config.payload == PayloadMode::Object {
+            // Nothing has been published until the documents go out
+            for metric in metrics {
+                let channel = format!("{}{}", &self.config.publish_channel, &metric.object);
+                self.announce(metric, &channel).await.map_err( |e| e.with_delivered(0) )?;
+            }
+            let mut published = HashSet::new();
+            for (object, document) in object_documents(&mut self.objects, metrics) {
+                let channel = format!("{}{}", &self.config.publish_channel, object);
+                println!("{} publish {} : {}", self.name(), channel, document);
+                if let Err(e) = self.publish(&channel, qos, retain, &document.to_string()).await {
+                    // A metric got there if its object's document did, and only the front of the batch counts
+                    let delivered = metrics.iter().take_while( |metric| published.contains(&metric.object) ).count();
+                    return Err(e.with_delivered(delivered));
+                }
+                published.insert(object);
+            }
+            return Ok(());
         }
+
+        for (index, metric) in metrics.iter().enumerate() {
+            let channel = format!("{}{}/{}",&self.config.publish_channel, &metric.object, &metric.property);
+            self.announce(metric, &channel).await.map_err( |e| e.with_delivered(index) )?;
+            let data = match self.config.payload {
+                PayloadMode::Json => metric_json(metric).to_string(),
+                _ => metric.value.to_string(),
+            };
+            println!("{} publish {} : {}", self.name(), channel, data);
+            self.publish(&channel, qos, retain, &data).await.map_err( |e| e.with_delivered(index) )?;
+        }
+        return Ok(());
     }
 
     fn shutdown(&mut self) -> Option<std::thread::JoinHandle<()>> {
         println!("{} - disconnecting", self.name());
-        self.client.disconnect().unwrap();
+        let status_topic = self.config.status_topic();
+        // Requests go out in the order they're queued, so the offline status is sent before the disconnect.
+        // try_publish, giving up after a few seconds, as a blocking publish could hold up the runtime thread for good.
+        let mut done = false;
+        for _ in 0..500 {
+            match self.client.try_publish(status_topic.clone(), qos_from_level(self.config.qos), true, STATUS_OFFLINE) {
+                Ok(()) => {
+                    done = true;
+                    break;
+                }
+                Err(ClientError::TryRequest(e)) if e.is_full() => thread::sleep(Duration::from_millis(10)),
+                Err(e) => {
+                    println!("{} - unable to publish status : {:?}", self.name(), e);
+                    done = true;
+                    break;
+                }
+            }
+        }
+        if !done {
+            println!("{} - request queue full, offline status not sent", self.name());
+        }
+        if let Err(e) = self.client.disconnect() {
+            println!("{} - unable to disconnect : {:?}", self.name(), e);
+        }
         return self.poller.take();
     }
 }
 
+
+#[test]
+fn test_object_documents() {
+    let earlier = chrono::DateTime::parse_from_rfc3339("2021-05-01T12:00:00Z").unwrap().with_timezone(&chrono::Utc);
+    let later = chrono::DateTime::parse_from_rfc3339("2021-05-01T12:00:05Z").unwrap().with_timezone(&chrono::Utc);
+    let mut objects = BTreeMap::new();
+    let documents = object_documents(&mut objects, &vec![
+        Metric::new("Bathroom", "Temperature", 21.5).with_timestamp(later),
+        Metric::new("Bathroom", "Humidity", 64).with_timestamp(earlier),
+        Metric::new("Door", "Open", true),
+    ]);
+    assert_eq!(documents.len(), 2);
+    assert_eq!(documents["Bathroom"], serde_json::json!({"values": {"Temperature": 21.5, "Humidity": 64}, "timestamp": "2021-05-01T12:00:05+00:00"}));
+    assert_eq!(documents["Door"], serde_json::json!({"values": {"Open": true}}));
+
+    // A later batch with only some of an object's properties keeps the rest, and a property can be called timestamp
+    let documents = object_documents(&mut objects, &vec![
+        Metric::new("Bathroom", "Humidity", 70),
+        Metric::new("Bathroom", "timestamp", 5),
+    ]);
+    assert_eq!(documents.len(), 1);
+    assert_eq!(documents["Bathroom"], serde_json::json!({"values": {"Temperature": 21.5, "Humidity": 70, "timestamp": 5}, "timestamp": "2021-05-01T12:00:05+00:00"}));
+
+    let mut config = DestinationMQTTConfig::example_config();
+    assert!(!config.retain());
+    config.payload = PayloadMode::Object;
+    assert!(config.retain());
+    config.publish.object = PublishConfig { retain: Some(false), qos: Some(0) };
+    assert!(!config.retain());
+    assert_eq!(config.state_qos(), QoS::AtMostOnce);
+}
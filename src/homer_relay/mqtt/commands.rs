@@ -0,0 +1,86 @@
+//! Remote control of the relay over MQTT.  A message on <topic>/<command> is passed to the Manager
+//! and the answer published to <reply_topic>/<command>.
+
+use serde::{Serialize, Deserialize};
+
+use super::RelayCommand;
+
+#[derive(Deserialize,Serialize,Default,Clone)]
+pub struct CommandConfig {
+    /// Commands we'll act on, from poll, reload, scan and status.  Nothing is subscribed to if empty.
+    #[serde(default)]
+    pub allow : Vec<String>,
+    /// Defaults to <publish_channel>cmd
+    #[serde(default)]
+    pub topic : Option<String>,
+    /// Defaults to <publish_channel>reply
+    #[serde(default)]
+    pub reply_topic : Option<String>,
+}
+
+impl CommandConfig {
+    pub fn enabled(&self) -> bool {
+        return !self.allow.is_empty();
+    }
+
+    pub fn command_topic(&self, publish_channel : &str) -> String {
+        match &self.topic {
+            Some(topic) => topic.trim_end_matches('/').to_string(),
+            None => format!("{}cmd", publish_channel)
+        }
+    }
+
+    pub fn reply_topic(&self, publish_channel : &str, command : &str) -> String {
+        match &self.reply_topic {
+            Some(topic) => format!("{}/{}", topic.trim_end_matches('/'), command),
+            None => format!("{}reply/{}", publish_channel, command)
+        }
+    }
+
+    /// None if the topic isn't one of ours, otherwise the command or why we won't run it
+    pub fn parse(&self, publish_channel : &str, topic : &str) -> Option<std::result::Result<RelayCommand, String>> {
+        let prefix = format!("{}/", self.command_topic(publish_channel));
+        let name = topic.strip_prefix(&prefix)?;
+        if !self.allow.iter().any( |allowed| allowed == name ) {
+            return Some(Err(format!("{} is not an allowed command", name)));
+        }
+        return Some(name.parse::<RelayCommand>().map_err( |e| e.to_string() ));
+    }
+}
+
+/// The answer to a command.  Anything sent with the command comes back as the id, so callers can match them up.
+pub fn reply_document(command : &str, ok : bool, answer : &str, request : &[u8]) -> serde_json::Value {
+    // Status comes back as JSON, which is nicer nested than quoted
+    let answer = serde_json::from_str::<serde_json::Value>(answer).unwrap_or(serde_json::json!(answer));
+    let mut document = serde_json::json!({
+        "command": command,
+        "ok": ok,
+        "answer": answer,
+    });
+    if !request.is_empty() {
+        document["id"] = serde_json::json!(String::from_utf8_lossy(request));
+    }
+    return document;
+}
+
+
+#[test]
+fn test_parse_commands() {
+    let config = CommandConfig { allow: vec!["poll".to_string(), "status".to_string(), "dance".to_string()], topic: None, reply_topic: None };
+    assert_eq!(config.parse("/MetricRelay/", "/MetricRelay/cmd/poll"), Some(Ok(RelayCommand::Poll)));
+    assert_eq!(config.parse("/MetricRelay/", "/MetricRelay/cmd/status"), Some(Ok(RelayCommand::Status)));
+    assert!(config.parse("/MetricRelay/", "/MetricRelay/cmd/reload").unwrap().is_err());
+    assert!(config.parse("/MetricRelay/", "/MetricRelay/cmd/dance").unwrap().is_err());
+    assert_eq!(config.parse("/MetricRelay/", "/MetricRelay/Shed/Temperature"), None);
+    assert_eq!(config.reply_topic("/MetricRelay/", "poll"), "/MetricRelay/reply/poll");
+
+    let custom = CommandConfig { allow: vec!["reload".to_string()], topic: Some("relay/pi/cmd/".to_string()), reply_topic: Some("relay/pi/reply".to_string()) };
+    assert_eq!(custom.parse("/MetricRelay/", "relay/pi/cmd/reload"), Some(Ok(RelayCommand::Reload)));
+    assert_eq!(custom.reply_topic("/MetricRelay/", "reload"), "relay/pi/reply/reload");
+}
+
+#[test]
+fn test_reply_document() {
+    assert_eq!(reply_document("status", true, "{\"uptime\":5}", b"42"), serde_json::json!({"command": "status", "ok": true, "answer": {"uptime": 5}, "id": "42"}));
+    assert_eq!(reply_document("poll", true, "polled 2 sources", b""), serde_json::json!({"command": "poll", "ok": true, "answer": "polled 2 sources"}));
+}
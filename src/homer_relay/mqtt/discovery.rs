@@ -0,0 +1,251 @@
+//! Home Assistant MQTT Discovery, see https://www.home-assistant.io/docs/mqtt/discovery/
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+use super::{Metric, MetricValue, STATUS_ONLINE, STATUS_OFFLINE};
+
+pub const DEFAULT_DISCOVERY_PREFIX : &str = "homeassistant";
+
+fn default_enabled() -> bool {
+    return true;
+}
+
+fn default_prefix() -> String {
+    return DEFAULT_DISCOVERY_PREFIX.to_string();
+}
+
+/// Overrides for what we'd otherwise guess from the property name
+#[derive(Deserialize,Serialize,Default,Clone)]
+pub struct SensorConfig {
+    #[serde(default)]
+    pub device_class : Option<String>,
+    #[serde(default)]
+    pub unit : Option<String>,
+}
+
+#[derive(Deserialize,Serialize)]
+pub struct DiscoveryConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled : bool,
+    #[serde(default = "default_prefix")]
+    pub prefix : String,
+    /// Groups our sensors in Home Assistant, defaults to the agent name
+    #[serde(default)]
+    pub node_id : Option<String>,
+    /// Keyed by property name
+    #[serde(default)]
+    pub sensors : HashMap<String, SensorConfig>,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> DiscoveryConfig {
+        return DiscoveryConfig {
+            enabled: default_enabled(),
+            prefix: default_prefix(),
+            node_id: None,
+            sensors: HashMap::new(),
+        }
+    }
+}
+
+/// Home Assistant only allows [a-zA-Z0-9_-] in node and object ids
+pub fn sanitise_id(id : &str) -> String {
+    return id.chars().map( |c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' } ).collect();
+}
+
+/// Best guess at the Home Assistant device class and unit for a property
+fn infer_sensor(property : &str) -> SensorConfig {
+    let lower = property.to_lowercase();
+    let (device_class, unit) = if lower.contains("temperature") || lower.contains("dewpoint") {
+        ("temperature", "°C")
+    } else if lower.contains("humidity") {
+        ("humidity", "%")
+    } else if lower.contains("pressure") {
+        ("pressure", "hPa")
+    } else if lower.contains("voltage") {
+        ("voltage", "V")
+    } else if lower.contains("battery") {
+        ("battery", "%")
+    } else if lower.contains("illuminance") {
+        ("illuminance", "lx")
+    } else if lower.contains("energy") {
+        ("energy", "kWh")
+    } else if lower.contains("power") && !lower.contains("txpower") {
+        ("power", "W")
+    } else if lower.contains("current") {
+        ("current", "A")
+    } else if lower.contains("co2") {
+        ("carbon_dioxide", "ppm")
+    } else {
+        return SensorConfig::default();
+    };
+    return SensorConfig { device_class: Some(device_class.to_string()), unit: Some(unit.to_string()) };
+}
+
+/// Best guess at the Home Assistant binary_sensor device class for an on/off property
+fn infer_binary_sensor(property : &str) -> Option<&'static str> {
+    let lower = property.to_lowercase();
+    let device_class = if lower.contains("charging") {
+        "battery_charging"
+    } else if lower.contains("battery") {
+        // On means low for the battery class
+        "battery"
+    } else if lower.contains("motion") {
+        "motion"
+    } else if lower.contains("occupancy") {
+        "occupancy"
+    } else if lower.contains("door") {
+        "door"
+    } else if lower.contains("window") {
+        "window"
+    } else if lower.contains("moisture") || lower.contains("leak") {
+        "moisture"
+    } else if lower.contains("power") {
+        "power"
+    } else if lower.contains("open") {
+        "opening"
+    } else {
+        return None;
+    };
+    return Some(device_class);
+}
+
+/// The Home Assistant component a metric shows up as.  On/off values need binary_sensor, as a sensor with
+/// a state class wants numbers.
+pub fn component(metric : &Metric) -> &'static str {
+    return match metric.value {
+        MetricValue::Boolean(_) => "binary_sensor",
+        _ => "sensor"
+    };
+}
+
+impl DiscoveryConfig {
+    pub fn config_topic(&self, node_id : &str, metric : &Metric) -> String {
+        return format!("{}/{}/{}/{}_{}/config", self.prefix, component(metric), sanitise_id(node_id), sanitise_id(&metric.object), sanitise_id(&metric.property));
+    }
+
+    /// Device class and unit for a metric: explicit config first, then the unit the source gave, then a guess.
+    /// On/off values never have a unit.
+    pub fn sensor(&self, metric : &Metric) -> SensorConfig {
+        let configured = self.sensors.get(&metric.property).cloned().unwrap_or_default();
+        if let MetricValue::Boolean(_) = metric.value {
+            return SensorConfig {
+                device_class: configured.device_class.or(infer_binary_sensor(&metric.property).map(String::from)),
+                unit: None,
+            };
+        }
+        let inferred = infer_sensor(&metric.property);
+        return SensorConfig {
+            device_class: configured.device_class.or(inferred.device_class.clone()),
+            unit: configured.unit.or(metric.unit.clone()).or(inferred.unit),
+        };
+    }
+
+    /// The retained document announcing a sensor.  value_template is needed when the state payload is JSON,
+    /// and for on/off values has to come out as true or false.
+    pub fn config_document(&self, node_id : &str, state_topic : &str, availability_topic : &str, metric : &Metric, value_template : Option<&str>) -> serde_json::Value {
+        let node_id = sanitise_id(node_id);
+        let object_id = sanitise_id(&metric.object);
+        let mut document = serde_json::json!({
+            "name": format!("{} {}", metric.object, metric.property),
+            "state_topic": state_topic,
+            "availability_topic": availability_topic,
+            "payload_available": STATUS_ONLINE,
+            "payload_not_available": STATUS_OFFLINE,
+            "unique_id": format!("{}_{}_{}", node_id, object_id, sanitise_id(&metric.property)),
+            "device": {
+                "identifiers": [format!("{}_{}", node_id, object_id)],
+                "name": metric.object,
+                "manufacturer": "homer_rust",
+            },
+        });
+
+        let sensor = self.sensor(metric);
+        let numeric = match metric.value { MetricValue::Integer(_) | MetricValue::Float(_) => true, _ => false };
+        if let MetricValue::Boolean(_) = metric.value {
+            document["payload_on"] = serde_json::json!(true.to_string());
+            document["payload_off"] = serde_json::json!(false.to_string());
+        }
+        if let Some(device_class) = sensor.device_class {
+            document["device_class"] = serde_json::json!(device_class);
+        }
+        if let Some(unit) = sensor.unit {
+            document["unit_of_measurement"] = serde_json::json!(unit);
+        }
+        if numeric {
+            document["state_class"] = serde_json::json!("measurement");
+        }
+        if let Some(value_template) = value_template {
+            document["value_template"] = serde_json::json!(value_template);
+        }
+        return document;
+    }
+}
+
+
+#[test]
+fn test_config_topic_and_document() {
+    let discovery = DiscoveryConfig::default();
+    let metric = Metric::new("Fish Tank", "Temperature", 24.5);
+    assert_eq!(discovery.config_topic("relay.pi", &metric), "homeassistant/sensor/relay_pi/Fish_Tank_Temperature/config");
+
+    let document = discovery.config_document("relay.pi", "/MetricRelay/Fish Tank/Temperature", "/MetricRelay/status", &metric, None);
+    assert_eq!(document, serde_json::json!({
+        "name": "Fish Tank Temperature",
+        "state_topic": "/MetricRelay/Fish Tank/Temperature",
+        "availability_topic": "/MetricRelay/status",
+        "payload_available": "online",
+        "payload_not_available": "offline",
+        "unique_id": "relay_pi_Fish_Tank_Temperature",
+        "device": {
+            "identifiers": ["relay_pi_Fish_Tank"],
+            "name": "Fish Tank",
+            "manufacturer": "homer_rust",
+        },
+        "device_class": "temperature",
+        "unit_of_measurement": "°C",
+        "state_class": "measurement",
+    }));
+}
+
+#[test]
+fn test_sensor_overrides() {
+    let mut discovery = DiscoveryConfig::default();
+    discovery.sensors.insert("Level".to_string(), SensorConfig { device_class: Some("distance".to_string()), unit: Some("cm".to_string()) });
+
+    let level = discovery.sensor(&Metric::new("Tank", "Level", 12));
+    assert_eq!(level.device_class, Some("distance".to_string()));
+    assert_eq!(level.unit, Some("cm".to_string()));
+
+    // The source's unit beats the guess
+    let voltage = discovery.sensor(&Metric::new("Tag", "BatteryVoltage", 2977).with_unit("mV"));
+    assert_eq!(voltage.device_class, Some("voltage".to_string()));
+    assert_eq!(voltage.unit, Some("mV".to_string()));
+
+    let text = discovery.config_document("node", "topic", "status", &Metric::new("Remote", "Button", "press"), Some("{{ value_json.value }}"));
+    assert!(text.get("state_class").is_none());
+    assert!(text.get("device_class").is_none());
+    assert_eq!(text["value_template"], "{{ value_json.value }}");
+}
+
+#[test]
+fn test_binary_sensors() {
+    let discovery = DiscoveryConfig::default();
+    let power = Metric::new("Kettle", "PowerOn", true);
+    assert_eq!(discovery.config_topic("node", &power), "homeassistant/binary_sensor/node/Kettle_PowerOn/config");
+
+    let document = discovery.config_document("node", "topic", "status", &power, None);
+    assert_eq!(document["device_class"], "power");
+    assert_eq!(document["payload_on"], "true");
+    assert_eq!(document["payload_off"], "false");
+    assert!(document.get("state_class").is_none());
+    assert!(document.get("unit_of_measurement").is_none());
+
+    let low = discovery.sensor(&Metric::new("Tag", "BatteryLow", false));
+    assert_eq!(low.device_class, Some("battery".to_string()));
+    assert_eq!(low.unit, None);
+    let charging = discovery.sensor(&Metric::new("Tag", "BatteryCharging", true).with_unit("%"));
+    assert_eq!(charging.device_class, Some("battery_charging".to_string()));
+    assert_eq!(charging.unit, None);
+}
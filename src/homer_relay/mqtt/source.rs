@@ -0,0 +1,340 @@
+//! Pick up readings other devices publish to the broker, e.g. Tasmota or ESPHome
+
+use rumqttc::{MqttOptions, Client, Event, Packet, Outgoing};
+use serde::{Serialize, Deserialize};
+use async_trait::async_trait;
+use chrono::Utc;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::*;
+
+fn default_port() -> u16 {
+    return 1883;
+}
+
+fn default_client_id() -> String {
+    return "homer_rust_source".to_string();
+}
+
+#[derive(Deserialize,Serialize,Clone)]
+pub struct SubscriptionConfig {
+    /// Topic to subscribe to, where a segment like +object or +property names what it matches,
+    /// e.g. "tele/+object/SENSOR" or "esphome/+object/sensor/+property/state"
+    pub pattern : String,
+    /// Object for every reading, if the pattern doesn't capture one
+    #[serde(default)]
+    pub object : Option<String>,
+    /// Property for a plain value, if the pattern doesn't capture one
+    #[serde(default)]
+    pub property : Option<String>,
+    /// Properties to take from a JSON payload, keyed by property with a dotted path, e.g. Power = "ENERGY.Power"
+    #[serde(default)]
+    pub values : BTreeMap<String, String>,
+    #[serde(default)]
+    pub unit : Option<String>,
+}
+
+#[derive(Deserialize,Serialize)]
+pub struct SourceMQTTConfig {
+    pub server : String,
+    #[serde(default = "default_port")]
+    pub port : u16,
+    /// Must be different from any destination's agent_name on the same broker
+    #[serde(default = "default_client_id")]
+    pub client_id : String,
+    #[serde(default)]
+    pub username : Option<String>,
+    #[serde(default)]
+    pub password : Option<String>,
+    /// PEM file for the broker's CA, turns on TLS
+    #[serde(default)]
+    pub ca_file : Option<String>,
+    /// PEM client certificate and key for mutual TLS
+    #[serde(default)]
+    pub client_cert_file : Option<String>,
+    #[serde(default)]
+    pub client_key_file : Option<String>,
+    #[serde(default = "default_qos")]
+    pub qos : u8,
+    /// Take readings from retained messages too.  These arrive on every connect and may be long out of date,
+    /// but they'd be timestamped as new.
+    #[serde(default)]
+    pub retained : bool,
+    pub subscriptions : Vec<SubscriptionConfig>,
+    #[serde(default)]
+    pub interval : Option<u64>,
+}
+
+impl SourceMQTTConfig {
+    pub fn example_config()->SourceMQTTConfig {
+        let mut values = BTreeMap::new();
+        values.insert("Power".to_string(), "ENERGY.Power".to_string());
+        values.insert("Voltage".to_string(), "ENERGY.Voltage".to_string());
+        return SourceMQTTConfig {
+            server: "localhost".to_string(),
+            port: default_port(),
+            client_id: default_client_id(),
+            username: None,
+            password: None,
+            ca_file: None,
+            client_cert_file: None,
+            client_key_file: None,
+            qos: default_qos(),
+            retained: false,
+            subscriptions: vec![
+                SubscriptionConfig {
+                    pattern: "tele/+object/SENSOR".to_string(),
+                    object: None,
+                    property: None,
+                    values,
+                    unit: None,
+                },
+                SubscriptionConfig {
+                    pattern: "esphome/+object/sensor/+property/state".to_string(),
+                    object: None,
+                    property: None,
+                    values: BTreeMap::new(),
+                    unit: None,
+                },
+            ],
+            interval: None,
+        }
+    }
+}
+
+/// What to subscribe to for a pattern, i.e. with the names taken off the wildcards
+pub fn topic_filter(pattern : &str) -> String {
+    let segments : Vec<&str> = pattern.split('/').map( |segment| if segment.starts_with('+') { "+" } else { segment } ).collect();
+    return segments.join("/");
+}
+
+/// The named segments of topic if it matches pattern
+pub fn match_topic(pattern : &str, topic : &str) -> Option<HashMap<String, String>> {
+    let mut captures = HashMap::new();
+    let mut topic_segments = topic.split('/');
+    for pattern_segment in pattern.split('/') {
+        if pattern_segment == "#" {
+            return Some(captures);
+        }
+        let topic_segment = topic_segments.next()?;
+        if let Some(name) = pattern_segment.strip_prefix('+') {
+            if !name.is_empty() {
+                captures.insert(name.to_string(), topic_segment.to_string());
+            }
+        } else if pattern_segment != topic_segment {
+            return None;
+        }
+    }
+    if topic_segments.next().is_some() {
+        return None;
+    }
+    return Some(captures);
+}
+
+/// Follow a dotted path like "ENERGY.Power" or "values.0" into a JSON document
+pub fn json_path<'a>(document : &'a serde_json::Value, path : &str) -> Option<&'a serde_json::Value> {
+    let mut value = document;
+    for key in path.split('.') {
+        value = match value {
+            serde_json::Value::Array(items) => items.get(key.parse::<usize>().ok()?)?,
+            _ => value.get(key)?
+        };
+    }
+    return Some(value);
+}
+
+fn json_value(value : &serde_json::Value) -> Option<MetricValue> {
+    return match value {
+        serde_json::Value::Bool(b) => Some(MetricValue::Boolean(*b)),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Some(MetricValue::Integer(i)),
+            None => n.as_f64().map(MetricValue::Float)
+        },
+        serde_json::Value::String(s) => Some(MetricValue::Text(s.clone())),
+        _ => None
+    };
+}
+
+impl SubscriptionConfig {
+    /// Turn a message into metrics, or nothing if it isn't for this subscription
+    pub fn readings(&self, topic : &str, payload : &[u8]) -> Vec<Metric> {
+        let captures = match match_topic(&self.pattern, topic) {
+            Some(captures) => captures,
+            None => return vec![]
+        };
+        let object = match captures.get("object").or(self.object.as_ref()) {
+            Some(object) => object.clone(),
+            None => topic.to_string()
+        };
+        let text = String::from_utf8_lossy(payload).trim().to_string();
+        let document : Option<serde_json::Value> = serde_json::from_str(&text).ok();
+
+        let mut readings : Vec<(String, MetricValue)> = vec![];
+        if self.values.is_empty() {
+            let property = captures.get("property").or(self.property.as_ref()).cloned().unwrap_or("Value".to_string());
+            let value = document.as_ref().and_then(json_value).unwrap_or(MetricValue::Text(text));
+            readings.push((property, value));
+        } else if let Some(document) = &document {
+            for (property, path) in &self.values {
+                match json_path(document, path).and_then(json_value) {
+                    Some(value) => readings.push((property.clone(), value)),
+                    None => log::debug!("mqtt - no {} in {}", path, topic)
+                }
+            }
+        } else {
+            log::warn!("mqtt - expected JSON on {} : {}", topic, text);
+        }
+
+        return readings.into_iter().map( |(property, value)| {
+            let metric = Metric::new(&object, &property, value);
+            match &self.unit {
+                Some(unit) => metric.with_unit(unit),
+                None => metric
+            }
+        }).collect();
+    }
+}
+
+pub struct SourceMQTT {
+    name : String,
+    client : Client,
+    poller : Option<std::thread::JoinHandle<()>>,
+    /// Latest reading for each object/property since the last poll
+    latest : Arc<Mutex<BTreeMap<(String, String), Metric>>>,
+}
+
+#[typetag::serde(name = "mqtt")]
+impl SourceConfig for SourceMQTTConfig {
+    fn name(&self) -> String {
+        return format!("mqtt source {}:{}", self.server, self.port);
+    }
+    fn interval(&self) -> Option<u64> {
+        return self.interval;
+    }
+    fn init(self : Box<Self>) -> Result<Box<dyn Source>> {
+        if self.subscriptions.is_empty() {
+            return Err(Error::Config("no subscriptions".to_string()));
+        }
+        let mut mqttoptions = MqttOptions::new(self.client_id.clone(), self.server.clone(), self.port);
+        if let Some(username) = &self.username {
+            mqttoptions.set_credentials(username.clone(), self.password.clone().unwrap_or_default());
+        }
+        set_tls(&mut mqttoptions, &self.ca_file, &self.client_cert_file, &self.client_key_file)?;
+        // Room for every subscribe request we make on connect, with some to spare for pings and acks
+        let (client, mut connection) = Client::new(mqttoptions, self.subscriptions.len() + 10);
+
+        let name = self.name();
+        let n = name.clone();
+        let mut subscribe_client = client.clone();
+        let qos = qos_from_level(self.qos);
+        let subscriptions = self.subscriptions.clone();
+        let retained = self.retained;
+        let latest = Arc::new(Mutex::new(BTreeMap::new()));
+        let latest_ref = Arc::clone(&latest);
+
+        let poller = thread::spawn( move || {
+            println!("{} : Poller thread started", n);
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        // Subscriptions don't survive a clean session, so ask again on every connect
+                        for subscription in &subscriptions {
+                            let filter = topic_filter(&subscription.pattern);
+                            println!("{} Subscribing to {}", n, filter);
+                            if let Err(e) = subscribe_client.try_subscribe(filter, qos) {
+                                println!("{} Unable to subscribe : {:?}", n, e);
+                            }
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(packet))) if packet.retain && !retained => {
+                        log::debug!("{} Skipping retained message on {}", n, packet.topic);
+                    }
+                    Ok(Event::Incoming(Packet::Publish(packet))) => {
+                        let now = Utc::now();
+                        let mut latest = latest_ref.lock().unwrap();
+                        for subscription in &subscriptions {
+                            for mut metric in subscription.readings(&packet.topic, &packet.payload) {
+                                metric.timestamp = Some(now);
+                                latest.insert((metric.object.clone(), metric.property.clone()), metric);
+                            }
+                        }
+                    }
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                        println!("{} Got disconnect", n);
+                        break;
+                    }
+                    Ok(_) => (),
+                    Err(e) => {
+                        println!("{} Error:{:?}", n, e);
+                    }
+                }
+            }
+            println!("{} Poll thread exiting", n);
+        });
+
+        return Ok(Box::new( SourceMQTT{
+            name,
+            client,
+            poller: Some(poller),
+            latest
+        } ))
+    }
+}
+
+#[async_trait]
+impl Source for SourceMQTT {
+    fn name(&self) -> &String {
+        return &self.name;
+    }
+    async fn poll(&mut self) -> Result<Vec<Metric>> {
+        let latest = std::mem::take(&mut *self.latest.lock().unwrap());
+        println!("{} - returning {} values", self.name(), latest.len());
+        return Ok(latest.into_iter().map( |(_, metric)| metric ).collect());
+    }
+    fn shutdown(&mut self) -> Option<std::thread::JoinHandle<()>> {
+        if let Err(e) = self.client.disconnect() {
+            println!("{} - unable to disconnect : {:?}", self.name(), e);
+        }
+        return self.poller.take();
+    }
+}
+
+
+#[test]
+fn test_match_topic() {
+    assert_eq!(topic_filter("esphome/+object/sensor/+property/state"), "esphome/+/sensor/+/state");
+    let captures = match_topic("esphome/+object/sensor/+property/state", "esphome/shed/sensor/temperature/state").unwrap();
+    assert_eq!(captures["object"], "shed");
+    assert_eq!(captures["property"], "temperature");
+    assert!(match_topic("tele/+object/SENSOR", "tele/plug/STATE").is_none());
+    assert!(match_topic("tele/+object/SENSOR", "tele/plug/SENSOR/extra").is_none());
+    assert!(match_topic("tele/+/SENSOR", "tele/plug/SENSOR").unwrap().is_empty());
+    assert!(match_topic("zigbee/#", "zigbee/kitchen/light").is_some());
+}
+
+#[test]
+fn test_readings() {
+    let mut values = BTreeMap::new();
+    values.insert("Power".to_string(), "ENERGY.Power".to_string());
+    values.insert("Missing".to_string(), "ENERGY.Nope".to_string());
+    values.insert("First".to_string(), "ENERGY.List.0".to_string());
+    let tasmota = SubscriptionConfig { pattern: "tele/+object/SENSOR".to_string(), object: None, property: None, values, unit: None };
+    let metrics = tasmota.readings("tele/kettle/SENSOR", br#"{"Time":"2021-05-01T12:00:00","ENERGY":{"Power":2150,"List":[1.5]}}"#);
+    let found : Vec<(String, String, String)> = metrics.iter().map( |m| (m.object.clone(), m.property.clone(), m.value.to_string()) ).collect();
+    assert_eq!(found, vec![
+        ("kettle".to_string(), "First".to_string(), "1.5".to_string()),
+        ("kettle".to_string(), "Power".to_string(), "2150".to_string()),
+    ]);
+    assert!(tasmota.readings("tele/kettle/SENSOR", b"not json").is_empty());
+    assert!(tasmota.readings("stat/kettle/POWER", b"ON").is_empty());
+
+    let esphome = SubscriptionConfig { pattern: "esphome/+object/sensor/+property/state".to_string(), object: None, property: None, values: BTreeMap::new(), unit: Some("°C".to_string()) };
+    let metrics = esphome.readings("esphome/shed/sensor/temperature/state", b"21.5");
+    assert_eq!(metrics[0].value, MetricValue::Float(21.5));
+    assert_eq!(metrics[0].unit, Some("°C".to_string()));
+
+    let power = SubscriptionConfig { pattern: "stat/+object/POWER".to_string(), object: None, property: Some("Power".to_string()), values: BTreeMap::new(), unit: None };
+    assert_eq!(power.readings("stat/kettle/POWER", b"ON")[0].value, MetricValue::Text("ON".to_string()));
+}
@@ -0,0 +1,180 @@
+pub use super::core::*;
+
+use serde::{Serialize, Deserialize};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::path::Path;
+
+pub const DEFAULT_ONEWIRE_PATH : &str = "/sys/bus/w1/devices";
+
+/// DS18B20 probes show up as 28-<serial>
+const DS18B20_PREFIX : &str = "28-";
+
+fn default_base_path() -> String {
+    return DEFAULT_ONEWIRE_PATH.to_string();
+}
+
+#[derive(Deserialize,Serialize)]
+pub struct SourceOneWireConfig {
+    #[serde(default = "default_base_path")]
+    base_path : String,
+    /// Friendly object names keyed by device id, e.g. "28-0316a2791cff" = "FishTank"
+    #[serde(default)]
+    aliases : HashMap<String, String>,
+    #[serde(default)]
+    interval : Option<u64>,
+}
+
+pub struct SourceOneWire {
+    config : Box<SourceOneWireConfig>,
+    name: String,
+}
+
+impl SourceOneWireConfig {
+    pub fn example_config()->SourceOneWireConfig {
+        let mut aliases = HashMap::new();
+        aliases.insert("28-0316a2791cff".to_string(), "FishTank".to_string());
+        return SourceOneWireConfig {
+            base_path: default_base_path(),
+            aliases,
+            interval: None
+        }
+    }
+}
+
+/// Millidegrees a DS18B20 reads before it has done a conversion
+const POWER_ON_RESET : i64 = 85000;
+
+/// Parse the contents of a w1_slave file, which look like:
+///
+/// ```text
+/// 72 01 4b 46 7f ff 0e 10 57 : crc=57 YES
+/// 72 01 4b 46 7f ff 0e 10 57 t=23125
+/// ```
+///
+/// Returns the temperature in degrees C.  85C is what the scratchpad holds at power on, so a sensor that lost
+/// power mid-read gives exactly that with a good CRC, and it's treated as a failed read.
+pub fn parse_w1_slave(contents : &str) -> Result<f64, String> {
+    let mut lines = contents.lines();
+    let crc_line = lines.next().ok_or("empty w1_slave file")?;
+    if !crc_line.trim_end().ends_with("YES") {
+        return Err(format!("CRC check failed : {}", crc_line));
+    }
+    let data_line = lines.next().ok_or("missing temperature line")?;
+    let position = data_line.find("t=").ok_or(format!("no t= in {}", data_line))?;
+    let millidegrees : i64 = data_line[position+2..].trim().parse().map_err( |e| format!("bad temperature {} : {:?}", data_line, e) )?;
+    if millidegrees == POWER_ON_RESET {
+        return Err("power on reset value, the sensor may have lost power".to_string());
+    }
+    return Ok(millidegrees as f64 / 1000.0);
+}
+
+impl SourceOneWire {
+    fn read_device(&self, device_path : &Path) -> Result<f64, String> {
+        let contents = std::fs::read_to_string(device_path.join("w1_slave")).map_err( |e| format!("{:?}", e) )?;
+        return parse_w1_slave(&contents);
+    }
+
+    pub fn read_devices(&self) -> Result<Vec<Metric>> {
+        let entries = std::fs::read_dir(&self.config.base_path)?;
+
+        let mut devices : Vec<String> = entries
+            .filter_map( |entry| entry.ok() )
+            .map( |entry| entry.file_name().to_string_lossy().to_string() )
+            .filter( |name| name.starts_with(DS18B20_PREFIX) )
+            .collect();
+        devices.sort();
+
+        let mut metrics = vec![];
+        for device in devices {
+            match self.read_device(&Path::new(&self.config.base_path).join(&device)) {
+                Ok(temperature) => {
+                    let object = self.config.aliases.get(&device).unwrap_or(&device);
+                    metrics.push(Metric::new(object, "Temperature", temperature).with_unit("°C"));
+                }
+                Err(e) => {
+                    log::warn!("{} - skipping {} : {}", self.name, device, e);
+                }
+            }
+        }
+        return Ok(metrics);
+    }
+}
+
+#[typetag::serde(name = "onewire")]
+impl SourceConfig for SourceOneWireConfig {
+    fn name(&self) -> String {
+        return format!("onewire {}", self.base_path);
+    }
+    fn interval(&self) -> Option<u64> {
+        return self.interval;
+    }
+    fn init(self : Box<Self>) -> Result<Box<dyn Source>> {
+        return Ok(Box::new( SourceOneWire{
+            name: self.name(),
+            config: self
+        } ))
+    }
+}
+
+#[async_trait]
+impl Source for SourceOneWire {
+    fn name(&self) -> &String {
+        return &self.name;
+    }
+    async fn poll(&mut self) -> Result<Vec<Metric>> {
+        return self.read_devices();
+    }
+}
+
+
+#[test]
+fn test_parse_w1_slave() {
+    let contents = "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+    assert_eq!(parse_w1_slave(contents), Ok(23.125));
+    let negative = "5e ff 4b 46 7f ff 0c 10 1c : crc=1c YES\n5e ff 4b 46 7f ff 0c 10 1c t=-10125\n";
+    assert_eq!(parse_w1_slave(negative), Ok(-10.125));
+}
+
+#[test]
+fn test_parse_w1_slave_crc_failure() {
+    let contents = "72 01 4b 46 7f ff 0e 10 57 : crc=00 NO\n72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+    assert!(parse_w1_slave(contents).is_err());
+    assert!(parse_w1_slave("").is_err());
+    assert!(parse_w1_slave("50 05 4b 46 7f ff 0c 10 1c : crc=1c YES\n50 05 4b 46 7f ff 0c 10 1c t=85000\n").is_err());
+}
+
+#[test]
+fn test_read_fake_devices() {
+    let base = std::env::temp_dir().join(format!("homer_onewire_{}", std::process::id()));
+    let write_device = |device : &str, contents : &str| {
+        std::fs::create_dir_all(base.join(device)).unwrap();
+        std::fs::write(base.join(device).join("w1_slave"), contents).unwrap();
+    };
+    write_device("28-000000000001", "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n72 01 4b 46 7f ff 0e 10 57 t=23125\n");
+    write_device("28-000000000002", "90 01 4b 46 7f ff 0c 10 2f : crc=2f YES\n90 01 4b 46 7f ff 0c 10 2f t=25000\n");
+    write_device("28-000000000004", "50 05 4b 46 7f ff 0c 10 1c : crc=1c YES\n50 05 4b 46 7f ff 0c 10 1c t=85000\n");
+    write_device("28-000000000003", "72 01 4b 46 7f ff 0e 10 57 : crc=00 NO\n72 01 4b 46 7f ff 0e 10 57 t=23125\n");
+    std::fs::create_dir_all(base.join("w1_bus_master1")).unwrap();
+
+    let mut aliases = HashMap::new();
+    aliases.insert("28-000000000002".to_string(), "FishTank".to_string());
+    let config = Box::new(SourceOneWireConfig {
+        base_path: base.to_string_lossy().to_string(),
+        aliases,
+        interval: None
+    });
+    let source = SourceOneWire { name: config.name(), config };
+    let metrics = source.read_devices().unwrap();
+    std::fs::remove_dir_all(&base).unwrap();
+
+    let readings : Vec<(String, Option<f64>)> = metrics.iter().map( |m| (m.object.clone(), m.value.as_f64()) ).collect();
+    assert_eq!(readings, vec![
+        ("28-000000000001".to_string(), Some(23.125)),
+        ("FishTank".to_string(), Some(25.0)),
+    ]);
+    assert_eq!(metrics[0].unit, Some("°C".to_string()));
+
+    // No 1-Wire bus at all is an error rather than an empty reading
+    assert!(source.read_devices().is_err());
+}
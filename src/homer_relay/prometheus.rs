@@ -0,0 +1,351 @@
+//! Serve the latest value of everything we've seen for Prometheus to scrape,
+//! see https://prometheus.io/docs/instrumenting/exposition_formats/
+
+pub use super::core::*;
+
+use serde::{Serialize, Deserialize};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn default_listen() -> String {
+    return "0.0.0.0:9184".to_string();
+}
+
+fn default_prefix() -> String {
+    return "homer_".to_string();
+}
+
+fn default_expiry() -> u64 {
+    return 300;
+}
+
+/// How long a scraper gets to send its request before we hang up
+const REQUEST_TIMEOUT : Duration = Duration::from_secs(10);
+
+#[derive(Deserialize,Serialize)]
+pub struct DestinationPrometheusConfig {
+    /// Address the /metrics listener binds to
+    #[serde(default = "default_listen")]
+    pub listen : String,
+    /// Put in front of every metric name
+    #[serde(default = "default_prefix")]
+    pub prefix : String,
+    /// Seconds without an update before a series is dropped
+    #[serde(default = "default_expiry")]
+    pub expiry : u64,
+    /// Extra labels on every series, e.g. site = "home"
+    #[serde(default)]
+    pub labels : BTreeMap<String, String>,
+}
+
+impl DestinationPrometheusConfig {
+    pub fn example_config()->DestinationPrometheusConfig {
+        return DestinationPrometheusConfig {
+            listen: default_listen(),
+            prefix: default_prefix(),
+            expiry: default_expiry(),
+            labels: BTreeMap::new(),
+        }
+    }
+}
+
+struct Sample {
+    metric : Metric,
+    updated : Instant,
+}
+
+/// Latest sample keyed by (property, object)
+type Samples = Arc<Mutex<BTreeMap<(String, String), Sample>>>;
+
+pub struct DestinationPrometheus {
+    name : String,
+    config : Box<DestinationPrometheusConfig>,
+    samples : Samples,
+    /// The server runs on its own thread, so shutdown can hand it to the Manager to wait for.  Otherwise the
+    /// listener could still be bound when a reload creates our replacement.
+    server : Option<std::thread::JoinHandle<()>>,
+    stop : Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+#[typetag::serde(name = "prometheus")]
+impl DestinationConfig for DestinationPrometheusConfig {
+    fn name(&self) -> String {
+        return format!("prometheus {}", self.listen);
+    }
+    fn init(self : Box<Self>) -> Result<Box<dyn Destination>> {
+        for label in self.labels.keys() {
+            if !is_label_name(label) || label == "object" {
+                return Err(Error::Config(format!("{} can't be used as a label name", label)));
+            }
+        }
+        let name = self.name();
+        let listener = std::net::TcpListener::bind(&self.listen)?;
+        listener.set_nonblocking(true)?;
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        println!("{} - serving /metrics", name);
+
+        let samples : Samples = Arc::new(Mutex::new(BTreeMap::new()));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let (n, prefix, labels, expiry, samples_ref) = (name.clone(), self.prefix.clone(), self.labels.clone(), Duration::from_secs(self.expiry), samples.clone());
+        let server = std::thread::spawn( move || {
+            runtime.block_on(async move {
+                let listener = match tokio::net::TcpListener::from_std(listener) {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        log::error!("{} - unable to listen : {:?}", n, e);
+                        return;
+                    }
+                };
+                tokio::select! {
+                    _ = serve(listener, n.clone(), prefix, labels, expiry, samples_ref) => (),
+                    _ = stopped => println!("{} - stopped serving", n),
+                }
+            });
+            // Dropping the runtime drops the listener and any scrapes still in progress
+        });
+
+        return Ok(Box::new( DestinationPrometheus{
+            name,
+            config: self,
+            samples,
+            server: Some(server),
+            stop: Some(stop)
+        } ))
+    }
+}
+
+/// Prometheus metric names are [a-zA-Z_:][a-zA-Z0-9_:]*, and by convention snake case
+pub fn metric_name(prefix : &str, property : &str) -> String {
+    let mut name = prefix.to_string();
+    let mut previous_lower = false;
+    for c in property.chars() {
+        if c.is_ascii_uppercase() && previous_lower {
+            name.push('_');
+        }
+        previous_lower = c.is_ascii_lowercase() || c.is_ascii_digit();
+        name.push(if c.is_ascii_alphanumeric() || c == '_' || c == ':' { c.to_ascii_lowercase() } else { '_' });
+    }
+    if name.chars().next().map_or(true, |c| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    return name;
+}
+
+/// Label names are [a-zA-Z_][a-zA-Z0-9_]*, and those starting with __ are kept for Prometheus itself
+pub fn is_label_name(label : &str) -> bool {
+    let mut chars = label.chars();
+    return match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all( |c| c.is_ascii_alphanumeric() || c == '_' ) && !label.starts_with("__"),
+        _ => false
+    };
+}
+
+/// HELP text only needs backslashes and newlines escaping
+fn help_text(text : &str) -> String {
+    return text.replace('\\', "\\\\").replace('\n', "\\n");
+}
+
+fn label_value(value : &str) -> String {
+    return value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+}
+
+/// Forget anything that hasn't been updated within expiry
+fn expire(samples : &mut BTreeMap<(String, String), Sample>, expiry : Duration, now : Instant) {
+    samples.retain( |_, sample| now.duration_since(sample.updated) <= expiry );
+}
+
+/// Everything we have in text exposition format, one gauge per property
+fn render(prefix : &str, labels : &BTreeMap<String, String>, samples : &BTreeMap<(String, String), Sample>) -> String {
+    let mut series : BTreeMap<String, Vec<&Metric>> = BTreeMap::new();
+    for sample in samples.values() {
+        if sample.metric.value.as_f64().is_some() {
+            series.entry(metric_name(prefix, &sample.metric.property)).or_default().push(&sample.metric);
+        }
+    }
+
+    let mut output = String::new();
+    for (name, metrics) in series {
+        let first = metrics[0];
+        match &first.unit {
+            Some(unit) => output.push_str(&format!("# HELP {} {}\n", name, help_text(&format!("{} ({})", first.property, unit)))),
+            None => output.push_str(&format!("# HELP {} {}\n", name, help_text(&first.property))),
+        }
+        output.push_str(&format!("# TYPE {} gauge\n", name));
+        for metric in metrics {
+            let mut metric_labels = vec![format!("object=\"{}\"", label_value(&metric.object))];
+            for (label, value) in labels {
+                metric_labels.push(format!("{}=\"{}\"", label, label_value(value)));
+            }
+            output.push_str(&format!("{}{{{}}} {}\n", name, metric_labels.join(","), metric.value.as_f64().unwrap_or_default()));
+        }
+    }
+    return output;
+}
+
+/// We only care about the request line, so read until the end of the headers or we've had enough
+async fn read_request(stream : &mut tokio::net::TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut request = vec![];
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any( |w| w == b"\r\n\r\n" ) && request.len() < 8192 {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    return Ok(request);
+}
+
+async fn handle(mut stream : tokio::net::TcpStream, prefix : &str, labels : &BTreeMap<String, String>, expiry : Duration, samples : &Samples) -> std::io::Result<()> {
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await
+        .map_err( |_| std::io::Error::new(std::io::ErrorKind::TimedOut, "no request") )??;
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, body) = if path == "/metrics" || path == "/" {
+        let mut samples = samples.lock().unwrap();
+        expire(&mut samples, expiry, Instant::now());
+        ("200 OK", render(prefix, labels, &samples))
+    } else {
+        ("404 Not Found", "Not found\n".to_string())
+    };
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+    stream.write_all(response.as_bytes()).await?;
+    return stream.shutdown().await;
+}
+
+async fn serve(listener : tokio::net::TcpListener, name : String, prefix : String, labels : BTreeMap<String, String>, expiry : Duration, samples : Samples) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                // Each on its own task, so one slow or stuck client can't hold up the rest
+                let (name, prefix, labels, samples) = (name.clone(), prefix.clone(), labels.clone(), samples.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, &prefix, &labels, expiry, &samples).await {
+                        log::warn!("{} - scrape from {} failed : {:?}", name, peer, e);
+                    }
+                });
+            }
+            Err(e) => {
+                log::error!("{} - accept failed : {:?}", name, e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Destination for DestinationPrometheus {
+    fn name(&self) -> &String {
+        return &self.name;
+    }
+    async fn report(&mut self, metrics: &Vec<Metric>) -> Result<()> {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        for metric in metrics {
+            samples.insert((metric.property.clone(), metric.object.clone()), Sample { metric: metric.clone(), updated: now });
+        }
+        expire(&mut samples, Duration::from_secs(self.config.expiry), now);
+        return Ok(());
+    }
+    fn shutdown(&mut self) -> Option<std::thread::JoinHandle<()>> {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        return self.server.take();
+    }
+}
+
+
+#[test]
+fn test_metric_name() {
+    assert_eq!(metric_name("homer_", "Temperature"), "homer_temperature");
+    assert_eq!(metric_name("homer_", "BatteryVoltage"), "homer_battery_voltage");
+    assert_eq!(metric_name("homer_", "Temperature2"), "homer_temperature2");
+    assert_eq!(metric_name("", "CO2 level"), "co2_level");
+    assert_eq!(metric_name("", "2nd"), "_2nd");
+}
+
+#[test]
+fn test_label_names() {
+    assert!(is_label_name("site"));
+    assert!(is_label_name("_rack2"));
+    assert!(!is_label_name("2nd"));
+    assert!(!is_label_name("data-centre"));
+    assert!(!is_label_name("__name__"));
+    assert!(!is_label_name(""));
+
+    let mut config = DestinationPrometheusConfig::example_config();
+    config.labels.insert("my site".to_string(), "home".to_string());
+    assert!(Box::new(config).init().is_err());
+}
+
+#[test]
+fn test_reinit_on_same_port() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut config = DestinationPrometheusConfig::example_config();
+    config.listen = format!("127.0.0.1:{}", port);
+    let mut first = Box::new(config).init().unwrap();
+    first.shutdown().unwrap().join().unwrap();
+
+    // As a reload does, straight after the old one has gone
+    let mut config = DestinationPrometheusConfig::example_config();
+    config.listen = format!("127.0.0.1:{}", port);
+    let mut second = Box::new(config).init().unwrap();
+    second.shutdown().unwrap().join().unwrap();
+}
+
+#[tokio::test]
+async fn test_stalled_client_does_not_block_scrapes() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let samples : Samples = Arc::new(Mutex::new(BTreeMap::new()));
+    samples.lock().unwrap().insert(("Temperature".to_string(), "Shed".to_string()), Sample { metric: Metric::new("Shed", "Temperature", 8), updated: Instant::now() });
+    let server = tokio::spawn(serve(listener, "prometheus".to_string(), "homer_".to_string(), BTreeMap::new(), Duration::from_secs(60), samples));
+
+    // Connects and never says anything
+    let _stalled = tokio::net::TcpStream::connect(address).await.unwrap();
+    let mut scraper = tokio::net::TcpStream::connect(address).await.unwrap();
+    scraper.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(5), scraper.read_to_string(&mut response)).await.unwrap().unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("homer_temperature{object=\"Shed\"} 8\n"));
+    server.abort();
+}
+
+#[test]
+fn test_render_and_expire() {
+    let start = Instant::now();
+    let mut samples = BTreeMap::new();
+    let mut add = |metric : Metric, updated : Instant| {
+        samples.insert((metric.property.clone(), metric.object.clone()), Sample { metric, updated });
+    };
+    add(Metric::new("Fish \"Tank\"", "Temperature", 24.5).with_unit("°C"), start);
+    add(Metric::new("Shed", "Temperature", 8), start + Duration::from_secs(100));
+    add(Metric::new("Door", "Open", true), start + Duration::from_secs(100));
+    add(Metric::new("Remote", "Button", "press"), start + Duration::from_secs(100));
+
+    let mut labels = BTreeMap::new();
+    labels.insert("site".to_string(), "home".to_string());
+    assert_eq!(render("homer_", &labels, &samples), concat!(
+        "# HELP homer_open Open\n",
+        "# TYPE homer_open gauge\n",
+        "homer_open{object=\"Door\",site=\"home\"} 1\n",
+        "# HELP homer_temperature Temperature (°C)\n",
+        "# TYPE homer_temperature gauge\n",
+        "homer_temperature{object=\"Fish \\\"Tank\\\"\",site=\"home\"} 24.5\n",
+        "homer_temperature{object=\"Shed\",site=\"home\"} 8\n",
+    ));
+
+    let mut awkward = BTreeMap::new();
+    awkward.insert(("a\\b\nc".to_string(), "x".to_string()), Sample { metric: Metric::new("x", "a\\b\nc", 1), updated: start });
+    assert!(render("", &BTreeMap::new(), &awkward).starts_with("# HELP a_b_c a\\\\b\\nc\n# TYPE a_b_c gauge\n"));
+
+    expire(&mut samples, Duration::from_secs(60), start + Duration::from_secs(120));
+    assert_eq!(samples.len(), 3);
+    assert!(!render("homer_", &BTreeMap::new(), &samples).contains("Fish"));
+}
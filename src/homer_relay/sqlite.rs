@@ -0,0 +1,344 @@
+//! Local history in SQLite.  Recent metrics are kept as they arrive, older ones are rolled up into
+//! hourly min/avg/max so the database doesn't grow forever on a Pi's SD card.
+
+pub use super::core::*;
+
+use serde::{Serialize, Deserialize};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{params, Connection};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_DATABASE : &str = "homer.db";
+
+/// How often old metrics are rolled up
+const DOWNSAMPLE_INTERVAL : Duration = Duration::from_secs(60 * 60);
+
+const SCHEMA : &str = "
+    CREATE TABLE IF NOT EXISTS metrics (
+        timestamp INTEGER NOT NULL,
+        object TEXT NOT NULL,
+        property TEXT NOT NULL,
+        value REAL,
+        text TEXT,
+        unit TEXT
+    );
+    CREATE INDEX IF NOT EXISTS metrics_series ON metrics (object, property, timestamp);
+    CREATE TABLE IF NOT EXISTS hourly (
+        hour INTEGER NOT NULL,
+        object TEXT NOT NULL,
+        property TEXT NOT NULL,
+        min REAL NOT NULL,
+        avg REAL NOT NULL,
+        max REAL NOT NULL,
+        count INTEGER NOT NULL,
+        unit TEXT,
+        PRIMARY KEY (object, property, hour)
+    );
+";
+
+fn default_path() -> String {
+    return DEFAULT_DATABASE.to_string();
+}
+
+fn default_raw_days() -> u64 {
+    return 7;
+}
+
+#[derive(Deserialize,Serialize)]
+pub struct DestinationSQLiteConfig {
+    #[serde(default = "default_path")]
+    pub path : String,
+    /// Days metrics are kept as they arrived before being rolled up into hourly min/avg/max
+    #[serde(default = "default_raw_days")]
+    pub raw_days : u64,
+    /// Days hourly figures are kept, forever if not set
+    #[serde(default)]
+    pub hourly_days : Option<u64>,
+}
+
+impl DestinationSQLiteConfig {
+    pub fn example_config()->DestinationSQLiteConfig {
+        return DestinationSQLiteConfig {
+            path: default_path(),
+            raw_days: default_raw_days(),
+            hourly_days: Some(365 * 2),
+        }
+    }
+}
+
+pub struct DestinationSQLite {
+    name : String,
+    config : Box<DestinationSQLiteConfig>,
+    connection : Connection,
+    last_downsample : Instant,
+}
+
+fn sql_error(e : rusqlite::Error) -> Error {
+    return Error::send(format!("SQLite : {}", e));
+}
+
+pub fn open_database(path : &str) -> Result<Connection> {
+    let connection = Connection::open(path).map_err( |e| Error::Config(format!("unable to open {} : {}", path, e)) )?;
+    connection.execute_batch(SCHEMA).map_err( |e| Error::Config(format!("unable to create tables in {} : {}", path, e)) )?;
+    return Ok(connection);
+}
+
+#[typetag::serde(name = "sqlite")]
+impl DestinationConfig for DestinationSQLiteConfig {
+    fn name(&self) -> String {
+        return format!("sqlite {}", self.path);
+    }
+    fn init(self : Box<Self>) -> Result<Box<dyn Destination>> {
+        let connection = open_database(&self.path)?;
+        let mut destination = DestinationSQLite {
+            name: self.name(),
+            config: self,
+            connection,
+            last_downsample: Instant::now(),
+        };
+        destination.downsample(Utc::now())?;
+        return Ok(Box::new(destination));
+    }
+}
+
+impl DestinationSQLite {
+    fn insert(&mut self, metrics : &Vec<Metric>, now : DateTime<Utc>) -> Result<()> {
+        let transaction = self.connection.transaction().map_err(sql_error)?;
+        {
+            let mut statement = transaction.prepare_cached("INSERT INTO metrics (timestamp, object, property, value, text, unit) VALUES (?1, ?2, ?3, ?4, ?5, ?6)").map_err(sql_error)?;
+            for metric in metrics {
+                let text = match &metric.value { MetricValue::Text(text) => Some(text.clone()), _ => None };
+                statement.execute(params![
+                    metric.timestamp.unwrap_or(now).timestamp_millis(),
+                    metric.object,
+                    metric.property,
+                    metric.value.as_f64(),
+                    text,
+                    metric.unit
+                ]).map_err(sql_error)?;
+            }
+        }
+        return transaction.commit().map_err(sql_error);
+    }
+
+    /// Roll whole hours older than raw_days into the hourly table, then drop anything past hourly_days
+    fn downsample(&mut self, now : DateTime<Utc>) -> Result<()> {
+        let cutoff = now.timestamp() - self.config.raw_days as i64 * 24 * 60 * 60;
+        let cutoff = (cutoff - cutoff.rem_euclid(3600)) * 1000;
+
+        let transaction = self.connection.transaction().map_err(sql_error)?;
+        // Merge with anything already there, in case late metrics arrive for an hour we've rolled up
+        let rolled_up = transaction.execute("
+            INSERT INTO hourly (hour, object, property, min, avg, max, count, unit)
+                SELECT (timestamp / 3600000) * 3600, object, property, MIN(value), AVG(value), MAX(value), COUNT(value), MAX(unit)
+                FROM metrics WHERE timestamp < ?1 AND value IS NOT NULL
+                GROUP BY timestamp / 3600000, object, property
+            ON CONFLICT (object, property, hour) DO UPDATE SET
+                min = MIN(min, excluded.min),
+                max = MAX(max, excluded.max),
+                avg = (avg * count + excluded.avg * excluded.count) / (count + excluded.count),
+                count = count + excluded.count",
+            params![cutoff]).map_err(sql_error)?;
+        let removed = transaction.execute("DELETE FROM metrics WHERE timestamp < ?1", params![cutoff]).map_err(sql_error)?;
+        if let Some(hourly_days) = self.config.hourly_days {
+            let hourly_cutoff = now.timestamp() - hourly_days as i64 * 24 * 60 * 60;
+            transaction.execute("DELETE FROM hourly WHERE hour < ?1", params![hourly_cutoff]).map_err(sql_error)?;
+        }
+        transaction.commit().map_err(sql_error)?;
+
+        if removed > 0 {
+            println!("{} - rolled {} metrics into {} hours", self.name, removed, rolled_up);
+        }
+        self.last_downsample = Instant::now();
+        return Ok(());
+    }
+}
+
+#[async_trait]
+impl Destination for DestinationSQLite {
+    fn name(&self) -> &String {
+        return &self.name;
+    }
+    async fn report(&mut self, metrics: &Vec<Metric>) -> Result<()> {
+        let now = Utc::now();
+        self.insert(metrics, now)?;
+        if self.last_downsample.elapsed() >= DOWNSAMPLE_INTERVAL {
+            self.downsample(now)?;
+        }
+        return Ok(());
+    }
+}
+
+/// A point in the history, either a metric as it arrived or an hour's summary
+pub struct HistoryRow {
+    pub time : DateTime<Utc>,
+    pub min : Option<f64>,
+    pub avg : Option<f64>,
+    pub max : Option<f64>,
+    pub count : i64,
+    pub text : Option<String>,
+    pub unit : Option<String>,
+}
+
+/// The path of the first sqlite destination in a configuration file, so it can be queried without saying where
+pub fn configured_path(config_content : &str) -> Option<String> {
+    let config : toml::Value = toml::from_str(config_content).ok()?;
+    let sqlite = config.get("destinations")?.as_array()?.iter()
+        .find( |destination| destination.get("type").and_then( |t| t.as_str() ) == Some("sqlite") )?;
+    return Some(sqlite.get("path").and_then( |path| path.as_str() ).map( |path| path.to_string() ).unwrap_or_else(default_path));
+}
+
+/// Everything recorded for object/property between from and to, oldest first
+pub fn query(connection : &Connection, object : &str, property : &str, from : DateTime<Utc>, to : DateTime<Utc>) -> Result<Vec<HistoryRow>> {
+    let mut statement = connection.prepare("
+        SELECT hour * 1000, min, avg, max, count, NULL, unit FROM hourly
+            WHERE object = ?1 AND property = ?2 AND hour * 1000 >= ?3 AND hour * 1000 <= ?4
+        UNION ALL
+        SELECT timestamp, value, value, value, 1, text, unit FROM metrics
+            WHERE object = ?1 AND property = ?2 AND timestamp >= ?3 AND timestamp <= ?4
+        ORDER BY 1").map_err(sql_error)?;
+    let rows = statement.query_map(params![object, property, from.timestamp_millis(), to.timestamp_millis()], |row| {
+        let millis : i64 = row.get(0)?;
+        Ok(HistoryRow {
+            time: Utc.timestamp_millis_opt(millis).single().ok_or(rusqlite::Error::IntegralValueOutOfRange(0, millis))?,
+            min: row.get(1)?,
+            avg: row.get(2)?,
+            max: row.get(3)?,
+            count: row.get(4)?,
+            text: row.get(5)?,
+            unit: row.get(6)?,
+        })
+    }).map_err(sql_error)?;
+    return rows.collect::<rusqlite::Result<Vec<HistoryRow>>>().map_err(sql_error);
+}
+
+fn format_number(value : Option<f64>) -> String {
+    return value.map( |v| format!("{}", (v * 1000.0).round() / 1000.0) ).unwrap_or_default();
+}
+
+impl HistoryRow {
+    fn fields(&self) -> Vec<String> {
+        let value = match &self.text {
+            Some(text) => text.clone(),
+            None => format_number(self.avg)
+        };
+        return vec![
+            self.time.to_rfc3339(),
+            value,
+            format_number(self.min),
+            format_number(self.max),
+            self.count.to_string(),
+            self.unit.clone().unwrap_or_default(),
+        ];
+    }
+}
+
+const HISTORY_COLUMNS : [&str; 6] = ["time", "value", "min", "max", "samples", "unit"];
+
+pub fn format_csv(rows : &Vec<HistoryRow>) -> String {
+    let mut output = format!("{}\n", HISTORY_COLUMNS.join(","));
+    for row in rows {
+        let fields : Vec<String> = row.fields().iter().map( |field| {
+            if field.contains(',') || field.contains('"') { format!("\"{}\"", field.replace('"', "\"\"")) } else { field.clone() }
+        }).collect();
+        output.push_str(&format!("{}\n", fields.join(",")));
+    }
+    return output;
+}
+
+pub fn format_table(rows : &Vec<HistoryRow>) -> String {
+    let all_fields : Vec<Vec<String>> = rows.iter().map( |row| row.fields() ).collect();
+    let widths : Vec<usize> = (0..HISTORY_COLUMNS.len()).map( |i| {
+        all_fields.iter().map( |fields| fields[i].chars().count() ).chain(std::iter::once(HISTORY_COLUMNS[i].len())).max().unwrap_or(0)
+    }).collect();
+
+    let line = |fields : Vec<String>| -> String {
+        let padded : Vec<String> = fields.iter().zip(&widths).map( |(field, width)| format!("{:<width$}", field, width = width) ).collect();
+        return format!("{}\n", padded.join("  ").trim_end());
+    };
+    let mut output = line(HISTORY_COLUMNS.iter().map( |c| c.to_string() ).collect());
+    for fields in all_fields {
+        output.push_str(&line(fields));
+    }
+    return output;
+}
+
+/// Either RFC3339 or a time ago like "30m", "12h" or "2d"
+pub fn parse_time(value : &str, now : DateTime<Utc>) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let bad_time = || Error::Parse(format!("expected RFC3339 or something like 12h, not \"{}\"", value));
+    if value.len() < 2 {
+        return Err(bad_time());
+    }
+    let (number, unit) = value.split_at(value.len() - 1);
+    let number : i64 = number.parse().map_err( |_| bad_time() )?;
+    let ago = match unit {
+        "s" => chrono::Duration::seconds(number),
+        "m" => chrono::Duration::minutes(number),
+        "h" => chrono::Duration::hours(number),
+        "d" => chrono::Duration::days(number),
+        _ => return Err(bad_time())
+    };
+    return Ok(now - ago);
+}
+
+
+#[cfg(test)]
+fn test_destination(raw_days : u64) -> DestinationSQLite {
+    let config = DestinationSQLiteConfig { path: ":memory:".to_string(), raw_days, hourly_days: Some(30) };
+    return DestinationSQLite { name: config.name(), connection: open_database(&config.path).unwrap(), config: Box::new(config), last_downsample: Instant::now() };
+}
+
+#[test]
+fn test_downsample_and_query() {
+    let now = Utc.with_ymd_and_hms(2021, 5, 10, 12, 0, 0).unwrap();
+    let mut destination = test_destination(1);
+    let at = |days : i64, hour : u32, minute : u32| Utc.with_ymd_and_hms(2021, 5, 10, hour, minute, 0).unwrap() - chrono::Duration::days(days);
+
+    destination.insert(&vec![
+        Metric::new("FishTank", "Temperature", 24.0).with_unit("°C").with_timestamp(at(3, 1, 0)),
+        Metric::new("FishTank", "Temperature", 25.0).with_unit("°C").with_timestamp(at(3, 1, 30)),
+        Metric::new("FishTank", "Temperature", 23.0).with_unit("°C").with_timestamp(at(3, 2, 0)),
+        Metric::new("Remote", "Button", "press").with_timestamp(at(3, 1, 0)),
+        Metric::new("FishTank", "Temperature", 22.0).with_unit("°C").with_timestamp(at(40, 1, 0)),
+    ], now).unwrap();
+    destination.downsample(now).unwrap();
+    // A late arrival for an hour that's already been rolled up
+    destination.insert(&vec![Metric::new("FishTank", "Temperature", 26.0).with_timestamp(at(3, 1, 45))], now).unwrap();
+    destination.insert(&vec![Metric::new("FishTank", "Temperature", 24.5).with_unit("°C")], now).unwrap();
+    destination.downsample(now).unwrap();
+
+    let rows = query(&destination.connection, "FishTank", "Temperature", now - chrono::Duration::days(7), now).unwrap();
+    assert_eq!(format_csv(&rows), concat!(
+        "time,value,min,max,samples,unit\n",
+        "2021-05-07T01:00:00+00:00,25,24,26,3,°C\n",
+        "2021-05-07T02:00:00+00:00,23,23,23,1,°C\n",
+        "2021-05-10T12:00:00+00:00,24.5,24.5,24.5,1,°C\n",
+    ));
+    let table = format_table(&rows);
+    assert!(table.starts_with("time                       value  min   max   samples  unit\n"));
+
+    // Text isn't rolled up, and everything older than hourly_days is gone
+    assert!(query(&destination.connection, "Remote", "Button", now - chrono::Duration::days(7), now).unwrap().is_empty());
+    assert!(query(&destination.connection, "FishTank", "Temperature", now - chrono::Duration::days(60), now - chrono::Duration::days(30)).unwrap().is_empty());
+}
+
+#[test]
+fn test_parse_time() {
+    let now = Utc.with_ymd_and_hms(2021, 5, 10, 12, 0, 0).unwrap();
+    assert_eq!(parse_time("12h", now).unwrap(), Utc.with_ymd_and_hms(2021, 5, 10, 0, 0, 0).unwrap());
+    assert_eq!(parse_time("2d", now).unwrap(), Utc.with_ymd_and_hms(2021, 5, 8, 12, 0, 0).unwrap());
+    assert_eq!(parse_time("2021-05-09T22:00:00+01:00", now).unwrap(), Utc.with_ymd_and_hms(2021, 5, 9, 21, 0, 0).unwrap());
+    assert!(parse_time("yesterday", now).is_err());
+    assert!(parse_time("h", now).is_err());
+}
+
+#[test]
+fn test_configured_path() {
+    assert_eq!(configured_path("[[destinations]]\ntype = \"log\"\n\n[[destinations]]\ntype = \"sqlite\"\npath = \"/var/lib/homer.db\"\n"), Some("/var/lib/homer.db".to_string()));
+    assert_eq!(configured_path("[[destinations]]\ntype = \"sqlite\"\n"), Some(DEFAULT_DATABASE.to_string()));
+    assert_eq!(configured_path("[[destinations]]\ntype = \"log\"\n"), None);
+}
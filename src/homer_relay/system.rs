@@ -0,0 +1,374 @@
+//! Health of the box the relay runs on, mostly from /proc, so we hear about full disks before they bite
+
+pub use super::core::*;
+
+use serde::{Serialize, Deserialize};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Instant;
+
+pub const DEFAULT_PROC_ROOT : &str = "/proc";
+
+fn default_object() -> String {
+    return "System".to_string();
+}
+
+fn default_proc_root() -> String {
+    return DEFAULT_PROC_ROOT.to_string();
+}
+
+fn default_mounts() -> Vec<String> {
+    return vec!["/".to_string()];
+}
+
+#[derive(Deserialize,Serialize)]
+pub struct SourceSystemConfig {
+    /// Object all the readings are published under
+    #[serde(default = "default_object")]
+    object : String,
+    #[serde(default = "default_proc_root")]
+    proc_root : String,
+    /// Mount points to report disk usage for
+    #[serde(default = "default_mounts")]
+    mounts : Vec<String>,
+    /// Network interfaces to leave out, e.g. lo
+    #[serde(default)]
+    ignore_interfaces : Vec<String>,
+    #[serde(default)]
+    interval : Option<u64>,
+}
+
+impl SourceSystemConfig {
+    pub fn example_config()->SourceSystemConfig {
+        return SourceSystemConfig {
+            object: default_object(),
+            proc_root: default_proc_root(),
+            mounts: default_mounts(),
+            ignore_interfaces: vec!["lo".to_string()],
+            interval: None
+        }
+    }
+}
+
+/// Jiffies from the cpu line of /proc/stat
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct CpuTimes {
+    pub busy : u64,
+    pub total : u64,
+}
+
+/// Byte counters for an interface from /proc/net/dev
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct NetCounters {
+    pub received : u64,
+    pub transmitted : u64,
+}
+
+pub struct SourceSystem {
+    config : Box<SourceSystemConfig>,
+    name: String,
+    /// Counters from the previous poll, which the CPU and network rates are worked out against
+    last_cpu : Option<CpuTimes>,
+    last_net : Option<(Instant, BTreeMap<String, NetCounters>)>,
+}
+
+/// The 1, 5 and 15 minute load averages from /proc/loadavg
+pub fn parse_loadavg(contents : &str) -> Result<[f64; 3], String> {
+    let mut fields = contents.split_whitespace().map( |field| field.parse::<f64>() );
+    let mut load = [0.0; 3];
+    for value in load.iter_mut() {
+        *value = fields.next().ok_or(format!("short loadavg {}", contents))?.map_err( |e| format!("bad loadavg {} : {:?}", contents, e) )?;
+    }
+    return Ok(load);
+}
+
+/// Values from /proc/meminfo in bytes, keyed by name e.g. MemAvailable
+pub fn parse_meminfo(contents : &str) -> BTreeMap<String, u64> {
+    let mut values = BTreeMap::new();
+    for line in contents.lines() {
+        let mut parts = line.splitn(2, ':');
+        let (key, rest) = match (parts.next(), parts.next()) {
+            (Some(key), Some(rest)) => (key.trim(), rest.trim()),
+            _ => continue
+        };
+        let mut fields = rest.split_whitespace();
+        if let Some(Ok(value)) = fields.next().map( |field| field.parse::<u64>() ) {
+            let multiplier = if fields.next() == Some("kB") { 1024 } else { 1 };
+            values.insert(key.to_string(), value * multiplier);
+        }
+    }
+    return values;
+}
+
+pub fn parse_cpu_times(contents : &str) -> Result<CpuTimes, String> {
+    let line = contents.lines().find( |line| line.starts_with("cpu ") ).ok_or("no cpu line in stat")?;
+    let jiffies : Vec<u64> = line.split_whitespace().skip(1).map( |field| field.parse::<u64>() ).collect::<Result<_, _>>()
+        .map_err( |e| format!("bad cpu line {} : {:?}", line, e) )?;
+    if jiffies.len() < 4 {
+        return Err(format!("short cpu line {}", line));
+    }
+    // user nice system idle iowait irq softirq steal, then guest time that's already counted in user and nice
+    let total : u64 = jiffies.iter().take(8).sum();
+    let idle = jiffies[3] + jiffies.get(4).unwrap_or(&0);
+    return Ok(CpuTimes { busy: total - idle, total });
+}
+
+/// Percentage of time the CPUs were busy between two readings of /proc/stat
+pub fn cpu_usage(previous : CpuTimes, current : CpuTimes) -> Option<f64> {
+    let total = current.total.checked_sub(previous.total)?;
+    let busy = current.busy.checked_sub(previous.busy)?;
+    if total == 0 {
+        return None;
+    }
+    return Some(100.0 * busy as f64 / total as f64);
+}
+
+pub fn parse_net_dev(contents : &str) -> BTreeMap<String, NetCounters> {
+    let mut interfaces = BTreeMap::new();
+    // Two header lines, then <interface>: <8 receive fields> <8 transmit fields>
+    for line in contents.lines().skip(2) {
+        let mut parts = line.splitn(2, ':');
+        let (interface, rest) = match (parts.next(), parts.next()) {
+            (Some(interface), Some(rest)) => (interface.trim(), rest),
+            _ => continue
+        };
+        let fields : Vec<u64> = rest.split_whitespace().filter_map( |field| field.parse().ok() ).collect();
+        if fields.len() >= 16 {
+            interfaces.insert(interface.to_string(), NetCounters { received: fields[0], transmitted: fields[8] });
+        }
+    }
+    return interfaces;
+}
+
+/// Total and available bytes on the filesystem holding path
+#[cfg(unix)]
+pub fn disk_space(path : &str) -> std::io::Result<(u64, u64)> {
+    let c_path = std::ffi::CString::new(path).map_err( |e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e) )?;
+    let mut stats : libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let block_size = stats.f_frsize as u64;
+    return Ok((stats.f_blocks as u64 * block_size, stats.f_bavail as u64 * block_size));
+}
+
+#[cfg(not(unix))]
+pub fn disk_space(_path : &str) -> std::io::Result<(u64, u64)> {
+    return Err(std::io::Error::new(std::io::ErrorKind::Other, "disk space is only available on unix"));
+}
+
+/// Mount points as they go in property names, which become topic segments, so "/" is root and "/mnt/data" mnt_data
+pub fn disk_name(mount : &str) -> String {
+    let name = mount.trim_matches('/').replace('/', "_");
+    if name.is_empty() {
+        return "root".to_string();
+    }
+    return name;
+}
+
+impl SourceSystem {
+    fn read(&self, file : &str) -> Result<String, String> {
+        return std::fs::read_to_string(Path::new(&self.config.proc_root).join(file)).map_err( |e| format!("{} : {:?}", file, e) );
+    }
+
+    fn metric<V : Into<MetricValue>>(&self, property : &str, value : V, unit : &str) -> Metric {
+        return Metric::new(&self.config.object, property, value).with_unit(unit);
+    }
+
+    fn read_load(&self, metrics : &mut Vec<Metric>) -> Result<(), String> {
+        let load = parse_loadavg(&self.read("loadavg")?)?;
+        metrics.push(Metric::new(&self.config.object, "Load1", load[0]));
+        metrics.push(Metric::new(&self.config.object, "Load5", load[1]));
+        metrics.push(Metric::new(&self.config.object, "Load15", load[2]));
+        return Ok(());
+    }
+
+    fn read_memory(&self, metrics : &mut Vec<Metric>) -> Result<(), String> {
+        let memory = parse_meminfo(&self.read("meminfo")?);
+        let total = *memory.get("MemTotal").ok_or("no MemTotal in meminfo")?;
+        // Older kernels don't have MemAvailable, so estimate it the way free used to
+        let available = match memory.get("MemAvailable") {
+            Some(available) => *available,
+            None => ["MemFree", "Buffers", "Cached"].iter().filter_map( |key| memory.get(*key) ).sum()
+        };
+        metrics.push(self.metric("MemoryAvailable", available as i64, "B"));
+        if total > 0 {
+            metrics.push(self.metric("MemoryUsed", 100.0 * total.saturating_sub(available) as f64 / total as f64, "%"));
+        }
+        if let (Some(swap_total), Some(swap_free)) = (memory.get("SwapTotal"), memory.get("SwapFree")) {
+            if *swap_total > 0 {
+                metrics.push(self.metric("SwapUsed", 100.0 * swap_total.saturating_sub(*swap_free) as f64 / *swap_total as f64, "%"));
+            }
+        }
+        return Ok(());
+    }
+
+    fn read_cpu(&mut self, metrics : &mut Vec<Metric>) -> Result<(), String> {
+        let current = parse_cpu_times(&self.read("stat")?)?;
+        if let Some(usage) = self.last_cpu.and_then( |previous| cpu_usage(previous, current) ) {
+            metrics.push(self.metric("CpuUsage", usage, "%"));
+        }
+        self.last_cpu = Some(current);
+        return Ok(());
+    }
+
+    fn read_network(&mut self, now : Instant, metrics : &mut Vec<Metric>) -> Result<(), String> {
+        let mut current = parse_net_dev(&self.read("net/dev")?);
+        current.retain( |interface, _| !self.config.ignore_interfaces.contains(interface) );
+        if let Some((then, previous)) = &self.last_net {
+            let seconds = now.duration_since(*then).as_secs_f64();
+            for (interface, counters) in &current {
+                let previous = match previous.get(interface) {
+                    Some(previous) if seconds > 0.0 => previous,
+                    _ => continue
+                };
+                // Counters going backwards means the interface was reset, so skip a reading
+                if let (Some(received), Some(transmitted)) = (counters.received.checked_sub(previous.received), counters.transmitted.checked_sub(previous.transmitted)) {
+                    metrics.push(self.metric(&format!("{} Received", interface), received as f64 / seconds, "B/s"));
+                    metrics.push(self.metric(&format!("{} Transmitted", interface), transmitted as f64 / seconds, "B/s"));
+                }
+            }
+        }
+        self.last_net = Some((now, current));
+        return Ok(());
+    }
+
+    fn read_disks(&self, metrics : &mut Vec<Metric>) {
+        for mount in &self.config.mounts {
+            match disk_space(mount) {
+                Ok((total, available)) => {
+                    metrics.push(self.metric(&format!("Disk {} Free", disk_name(mount)), available as i64, "B"));
+                    if total > 0 {
+                        metrics.push(self.metric(&format!("Disk {} Used", disk_name(mount)), 100.0 * (total - available) as f64 / total as f64, "%"));
+                    }
+                }
+                Err(e) => {
+                    log::warn!("{} - skipping disk {} : {}", self.name, mount, e);
+                }
+            }
+        }
+    }
+
+    fn read_uptime(&self, metrics : &mut Vec<Metric>) -> Result<(), String> {
+        let contents = self.read("uptime")?;
+        let seconds : f64 = contents.split_whitespace().next().unwrap_or("").parse().map_err( |e| format!("bad uptime {} : {:?}", contents, e) )?;
+        metrics.push(self.metric("Uptime", seconds as i64, "s"));
+        return Ok(());
+    }
+
+    /// Everything we can read, skipping any part that fails.  CPU usage and network rates need a previous
+    /// poll to compare with, so only show up from the second one.
+    pub fn read_system(&mut self, now : Instant) -> Vec<Metric> {
+        let mut metrics = vec![];
+        let results = vec![
+            self.read_load(&mut metrics),
+            self.read_memory(&mut metrics),
+            self.read_cpu(&mut metrics),
+            self.read_network(now, &mut metrics),
+            self.read_uptime(&mut metrics),
+        ];
+        for e in results.into_iter().filter_map( |result| result.err() ) {
+            log::warn!("{} - {}", self.name, e);
+        }
+        self.read_disks(&mut metrics);
+        return metrics;
+    }
+}
+
+#[typetag::serde(name = "system")]
+impl SourceConfig for SourceSystemConfig {
+    fn name(&self) -> String {
+        return format!("system {}", self.object);
+    }
+    fn interval(&self) -> Option<u64> {
+        return self.interval;
+    }
+    fn init(self : Box<Self>) -> Result<Box<dyn Source>> {
+        return Ok(Box::new( SourceSystem{
+            name: self.name(),
+            config: self,
+            last_cpu: None,
+            last_net: None
+        } ))
+    }
+}
+
+#[async_trait]
+impl Source for SourceSystem {
+    fn name(&self) -> &String {
+        return &self.name;
+    }
+    async fn poll(&mut self) -> Result<Vec<Metric>> {
+        return Ok(self.read_system(Instant::now()));
+    }
+}
+
+
+#[test]
+fn test_parse_proc_files() {
+    assert_eq!(parse_loadavg("0.52 0.58 0.59 1/389 12345\n"), Ok([0.52, 0.58, 0.59]));
+    assert!(parse_loadavg("0.52").is_err());
+
+    let memory = parse_meminfo("MemTotal:        3884096 kB\nMemAvailable:    2933420 kB\nHugePages_Total:       0\n");
+    assert_eq!(memory["MemTotal"], 3884096 * 1024);
+    assert_eq!(memory["HugePages_Total"], 0);
+
+    let before = parse_cpu_times("cpu  100 0 50 800 50 0 0 0 0 0\ncpu0 100 0 50 800 50 0 0 0 0 0\n").unwrap();
+    assert_eq!(before, CpuTimes { busy: 150, total: 1000 });
+    let after = parse_cpu_times("cpu  200 0 100 850 50 0 0 0 0 0\n").unwrap();
+    assert_eq!(cpu_usage(before, after), Some(75.0));
+    assert_eq!(cpu_usage(after, before), None);
+
+    assert_eq!(disk_name("/"), "root");
+    assert_eq!(disk_name("/mnt/data/"), "mnt_data");
+}
+
+#[test]
+fn test_read_fixture_proc() {
+    let root = std::env::temp_dir().join(format!("homer_system_{}", std::process::id()));
+    let write = |path : &str, contents : &str| {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    };
+    let net_dev = |eth0_rx : u64, eth0_tx : u64| format!(
+        "Inter-|   Receive                                                |  Transmit\n face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed\n    lo: 5000 50 0 0 0 0 0 0 5000 50 0 0 0 0 0 0\n  eth0: {} 10 0 0 0 0 0 0 {} 10 0 0 0 0 0 0\n",
+        eth0_rx, eth0_tx);
+    write("loadavg", "0.52 0.58 0.59 1/389 12345\n");
+    write("meminfo", "MemTotal:        1000 kB\nMemFree:          100 kB\nMemAvailable:     250 kB\nSwapTotal:        400 kB\nSwapFree:         300 kB\n");
+    write("stat", "cpu  100 0 50 800 50 0 0 0 0 0\n");
+    write("net/dev", &net_dev(1000, 500));
+    write("uptime", "12345.67 40000.00\n");
+
+    let mut config = SourceSystemConfig::example_config();
+    config.object = "RelayBox".to_string();
+    config.proc_root = root.to_string_lossy().to_string();
+    config.mounts = vec![root.to_string_lossy().to_string(), "/no/such/mount".to_string()];
+    let config = Box::new(config);
+    let mut source = SourceSystem { name: config.name(), config, last_cpu: None, last_net: None };
+
+    let properties = |metrics : &Vec<Metric>| -> BTreeMap<String, String> {
+        metrics.iter().map( |m| (m.property.clone(), m.value.to_string()) ).collect()
+    };
+    let start = Instant::now();
+    let first = properties(&source.read_system(start));
+    assert_eq!(first["Load5"], "0.58");
+    assert_eq!(first["MemoryAvailable"], (250 * 1024).to_string());
+    assert_eq!(first["MemoryUsed"], "75");
+    assert_eq!(first["SwapUsed"], "25");
+    assert_eq!(first["Uptime"], "12345");
+    assert!(first.contains_key(&format!("Disk {} Free", disk_name(&root.to_string_lossy()))));
+    assert!(first.keys().all( |property| !property.contains('/') ));
+    assert!(!first.contains_key("CpuUsage"));
+    assert!(!first.contains_key("eth0 Received"));
+
+    write("stat", "cpu  200 0 100 850 50 0 0 0 0 0\n");
+    write("net/dev", &net_dev(3000, 1500));
+    let second = properties(&source.read_system(start + std::time::Duration::from_secs(2)));
+    std::fs::remove_dir_all(&root).unwrap();
+    assert_eq!(second["CpuUsage"], "75");
+    assert_eq!(second["eth0 Received"], "1000");
+    assert_eq!(second["eth0 Transmitted"], "500");
+    assert!(!second.contains_key("lo Received"));
+}
@@ -0,0 +1,35 @@
+//! Helpers shared by tests in more than one module
+
+/// Accepts one connection per status code, answering with that status and returning what was sent
+pub async fn stub_http_server(statuses : Vec<u16>) -> (String, tokio::task::JoinHandle<Vec<(String, Vec<u8>)>>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        let mut requests = vec![];
+        for status in statuses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buffer = [0u8; 4096];
+            let header_end = loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                if let Some(position) = request.windows(4).position( |w| w == b"\r\n\r\n" ) {
+                    break position + 4;
+                }
+            };
+            let head = String::from_utf8_lossy(&request[..header_end]).to_string();
+            let length : usize = head.lines()
+                .find_map( |line| line.to_lowercase().strip_prefix("content-length:").map( |value| value.trim().parse().unwrap() ) )
+                .unwrap_or(0);
+            while request.len() < header_end + length {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            stream.write_all(format!("HTTP/1.1 {} Stub\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).as_bytes()).await.unwrap();
+            requests.push((head, request[header_end..].to_vec()));
+        }
+        requests
+    });
+    return (url, server);
+}
@@ -0,0 +1,275 @@
+//! POST metrics to anything that takes an HTTP request, e.g. Node-RED or n8n
+
+pub use super::core::*;
+use super::buffer::BufferConfig;
+use super::http::send_with_retry;
+
+use serde::{Serialize, Deserialize};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+fn default_method() -> String {
+    return "POST".to_string();
+}
+
+fn default_retries() -> u32 {
+    return 2;
+}
+
+fn default_timeout() -> u64 {
+    return 10;
+}
+
+#[derive(Deserialize,Serialize,Clone,Copy,Debug,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookMode {
+    /// One request per metric, with the body from the template
+    Single,
+    /// One request per report, with a JSON array of metrics as the body
+    Batch,
+}
+
+impl Default for WebhookMode {
+    fn default() -> WebhookMode {
+        return WebhookMode::Single;
+    }
+}
+
+#[derive(Deserialize,Serialize,Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum WebhookAuth {
+    Basic { username : String, password : Option<String> },
+    Bearer { token : String },
+}
+
+#[derive(Deserialize,Serialize)]
+pub struct DestinationWebhookConfig {
+    pub url : String,
+    #[serde(default = "default_method")]
+    pub method : String,
+    #[serde(default)]
+    pub headers : BTreeMap<String, String>,
+    /// Body for each metric in single mode, with {{object}}, {{property}}, {{value}}, {{unit}}, {{timestamp}} and {{source}}
+    /// replaced, escaped to go inside a JSON string unless the Content-Type isn't JSON.  {{value_json}} is the value
+    /// as a JSON number, true/false or string.  The metric as JSON if not set.
+    #[serde(default)]
+    pub template : Option<String>,
+    #[serde(default)]
+    pub mode : WebhookMode,
+    #[serde(default)]
+    pub auth : Option<WebhookAuth>,
+    /// Seconds to wait for a response
+    #[serde(default = "default_timeout")]
+    pub timeout : u64,
+    /// Further attempts after a server error before giving up.  These hold up every other destination, so keep
+    /// them few and leave longer outages to the buffer.
+    #[serde(default = "default_retries")]
+    pub retries : u32,
+    /// Keep metrics on disk while the webhook is unreachable
+    #[serde(default)]
+    pub buffer : Option<BufferConfig>,
+}
+
+impl DestinationWebhookConfig {
+    pub fn example_config()->DestinationWebhookConfig {
+        return DestinationWebhookConfig {
+            url: "http://localhost:1880/homer".to_string(),
+            method: default_method(),
+            headers: BTreeMap::new(),
+            template: Some("{\"topic\":\"{{object}}/{{property}}\",\"payload\":{{value_json}}}".to_string()),
+            mode: WebhookMode::Single,
+            auth: None,
+            timeout: default_timeout(),
+            retries: default_retries(),
+            buffer: None,
+        }
+    }
+}
+
+pub struct DestinationWebhook {
+    name : String,
+    config : Box<DestinationWebhookConfig>,
+    client : reqwest::Client,
+    method : reqwest::Method,
+    /// Whether the body is JSON, so template values need escaping
+    json : bool,
+}
+
+/// Escape text to go between the quotes of a JSON string
+fn json_escape(text : &str) -> String {
+    let quoted = serde_json::to_string(text).unwrap_or_default();
+    return quoted[1..quoted.len()-1].to_string();
+}
+
+/// Fill in the {{placeholders}} in a template from a metric, escaping them for a JSON string if json is set
+pub fn render_template(template : &str, metric : &Metric, json : bool) -> Result<String> {
+    let mut output = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let end = rest[start..].find("}}").ok_or(Error::Config(format!("unclosed {{{{ in template {}", template)))?;
+        let placeholder = rest[start+2..start+end].trim();
+        let value = match placeholder {
+            "object" => metric.object.clone(),
+            "property" => metric.property.clone(),
+            "value" => metric.value.to_string(),
+            "unit" => metric.unit.clone().unwrap_or_default(),
+            "timestamp" => metric.timestamp.map( |t| t.to_rfc3339() ).unwrap_or_default(),
+            "source" => metric.source.clone().unwrap_or_default(),
+            "value_json" => {
+                output.push_str(&serde_json::to_string(&metric.value)?);
+                rest = &rest[start+end+2..];
+                continue;
+            }
+            _ => return Err(Error::Config(format!("unknown placeholder {{{{{}}}}} in template", placeholder)))
+        };
+        output.push_str(&if json { json_escape(&value) } else { value });
+        rest = &rest[start+end+2..];
+    }
+    output.push_str(rest);
+    return Ok(output);
+}
+
+#[typetag::serde(name = "webhook")]
+impl DestinationConfig for DestinationWebhookConfig {
+    fn name(&self) -> String {
+        return format!("webhook {}", self.url);
+    }
+    fn buffer(&self) -> Option<BufferConfig> {
+        return self.buffer.clone();
+    }
+    fn init(self : Box<Self>) -> Result<Box<dyn Destination>> {
+        let json = match self.headers.iter().find( |(key, _)| key.eq_ignore_ascii_case("content-type") ) {
+            Some((_, content_type)) => content_type.to_lowercase().contains("json"),
+            None => true
+        };
+        if let Some(template) = &self.template {
+            // Find out about typos now rather than on every report
+            render_template(template, &Metric::new("object", "property", 0), json)?;
+        }
+        let method = reqwest::Method::from_bytes(self.method.to_uppercase().as_bytes())
+            .map_err( |_| Error::Config(format!("bad HTTP method {}", self.method)) )?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(self.timeout))
+            .build()
+            .map_err( |e| Error::Config(format!("HTTP client : {}", e)) )?;
+        return Ok(Box::new( DestinationWebhook{
+            name: self.name(),
+            config: self,
+            client,
+            method,
+            json
+        } ))
+    }
+}
+
+impl DestinationWebhook {
+    fn request(&self, body : String) -> reqwest::RequestBuilder {
+        let mut request = self.client.request(self.method.clone(), &self.config.url);
+        if !self.config.headers.keys().any( |key| key.eq_ignore_ascii_case("content-type") ) {
+            request = request.header("Content-Type", "application/json");
+        }
+        for (key, value) in &self.config.headers {
+            request = request.header(key.as_str(), value.as_str());
+        }
+        request = match &self.config.auth {
+            Some(WebhookAuth::Basic { username, password }) => request.basic_auth(username, password.as_ref()),
+            Some(WebhookAuth::Bearer { token }) => request.bearer_auth(token),
+            None => request
+        };
+        return request.body(body);
+    }
+}
+
+#[async_trait]
+impl Destination for DestinationWebhook {
+    fn name(&self) -> &String {
+        return &self.name;
+    }
+    async fn report(&mut self, metrics: &Vec<Metric>) -> Result<()> {
+        if metrics.is_empty() {
+            return Ok(());
+        }
+        match self.config.mode {
+            WebhookMode::Batch => {
+                send_with_retry(&self.name, self.request(serde_json::to_string(metrics)?), self.config.retries).await?;
+            }
+            WebhookMode::Single => {
+                for (index, metric) in metrics.iter().enumerate() {
+                    let body = match &self.config.template {
+                        Some(template) => render_template(template, metric, self.json),
+                        None => serde_json::to_string(metric).map_err(Error::from)
+                    };
+                    let sent = match body {
+                        Ok(body) => send_with_retry(&self.name, self.request(body), self.config.retries).await,
+                        Err(e) => Err(e)
+                    };
+                    // Earlier metrics got there, so only those after them are worth keeping
+                    sent.map_err( |e| e.with_delivered(index) )?;
+                }
+            }
+        }
+        println!("{} - sent {} metrics", self.name, metrics.len());
+        return Ok(());
+    }
+}
+
+
+#[test]
+fn test_render_template() {
+    let metric = Metric::new("FishTank", "Temperature", 24.5).with_unit("°C");
+    assert_eq!(render_template("{{object}}/{{ property }} = {{value}}{{unit}}", &metric, false).unwrap(), "FishTank/Temperature = 24.5°C");
+    assert_eq!(render_template("no placeholders", &metric, true).unwrap(), "no placeholders");
+    assert!(render_template("{{colour}}", &metric, true).is_err());
+    assert!(render_template("{{object", &metric, true).is_err());
+
+    // Whatever's in the metric, a JSON template stays JSON
+    let awkward = Metric::new("Say \"hi\"\\", "Button", "on\noff");
+    let body = render_template("{\"topic\":\"{{object}}/{{property}}\",\"text\":\"{{value}}\",\"payload\":{{value_json}}}", &awkward, true).unwrap();
+    let parsed : serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(parsed, serde_json::json!({"topic": "Say \"hi\"\\/Button", "text": "on\noff", "payload": "on\noff"}));
+    assert_eq!(render_template("{{value_json}}", &Metric::new("Door", "Open", true), true).unwrap(), "true");
+}
+
+#[tokio::test]
+async fn test_webhook_requests() {
+    let (url, server) = super::test_support::stub_http_server(vec![502, 200, 200]).await;
+    let mut config = DestinationWebhookConfig::example_config();
+    config.url = format!("{}/hook", url);
+    config.method = "put".to_string();
+    config.auth = Some(WebhookAuth::Bearer { token: "secret".to_string() });
+    config.headers.insert("X-Relay".to_string(), "homer".to_string());
+    let mut destination = Box::new(config).init().unwrap();
+    destination.report(&vec![Metric::new("Shed", "Temperature", 8.5), Metric::new("Shed", "Door", true)]).await.unwrap();
+
+    let requests = server.await.unwrap();
+    let bodies : Vec<String> = requests.iter().map( |(_, body)| String::from_utf8_lossy(body).to_string() ).collect();
+    assert_eq!(bodies, vec![
+        "{\"topic\":\"Shed/Temperature\",\"payload\":8.5}",
+        "{\"topic\":\"Shed/Temperature\",\"payload\":8.5}",
+        "{\"topic\":\"Shed/Door\",\"payload\":true}",
+    ]);
+    let head = requests[2].0.to_lowercase();
+    assert!(head.starts_with("put /hook "));
+    assert!(head.contains("authorization: bearer secret"));
+    assert!(head.contains("x-relay: homer"));
+    assert!(head.contains("content-type: application/json"));
+}
+
+#[tokio::test]
+async fn test_webhook_batch() {
+    let (url, server) = super::test_support::stub_http_server(vec![200]).await;
+    let mut config = DestinationWebhookConfig::example_config();
+    config.url = url;
+    config.mode = WebhookMode::Batch;
+    config.auth = Some(WebhookAuth::Basic { username: "homer".to_string(), password: Some("pass".to_string()) });
+    let mut destination = Box::new(config).init().unwrap();
+    destination.report(&vec![Metric::new("Shed", "Temperature", 8.5), Metric::new("Shed", "Humidity", 80)]).await.unwrap();
+
+    let (head, body) = &server.await.unwrap()[0];
+    assert!(head.to_lowercase().contains(&"authorization: basic aG9tZXI6cGFzcw==".to_lowercase()));
+    let sent : Vec<Metric> = serde_json::from_slice(body).unwrap();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[1].value, MetricValue::Integer(80));
+}
@@ -8,9 +8,18 @@ use log;
 
 // use homer_relay::core::*;
 use homer_relay::log::*;
+use homer_relay::file::*;
+use homer_relay::sqlite::*;
 use homer_relay::mqtt::*;
+use homer_relay::mqtt::source::*;
 use homer_relay::cloudwatch::*;
+use homer_relay::prometheus::*;
+use homer_relay::influxdb::*;
+use homer_relay::webhook::*;
 use homer_relay::constant::*;
+use homer_relay::onewire::*;
+use homer_relay::hwmon::*;
+use homer_relay::system::*;
 use homer_relay::bluetooth::*;
 
 /// Search for a pattern in a file and display the lines that contain it.
@@ -33,11 +42,26 @@ enum Command {
         #[structopt(name = "id", long = "id")]
         id: String,
     },
-    #[structopt(about = "Run the thing")]
+    #[structopt(about = "Run the thing, polling sources until Ctrl-C")]
     Run {
     },
     #[structopt(about = "Write an example configuration file")]
     WriteExampleConfig {
+    },
+    #[structopt(about = "Print the history of a property from a sqlite destination")]
+    Query {
+        #[structopt(long = "object")]
+        object: String,
+        #[structopt(long = "property")]
+        property: String,
+        #[structopt(long = "from", default_value = "24h", help = "RFC3339, or a time ago like 30m, 12h or 2d")]
+        from: String,
+        #[structopt(long = "to", help = "RFC3339, or a time ago like 30m, 12h or 2d, defaults to now")]
+        to: Option<String>,
+        #[structopt(long = "database", help = "Defaults to the path of the sqlite destination in the config")]
+        database: Option<String>,
+        #[structopt(long = "csv")]
+        csv: bool,
     }
 }
 
@@ -53,22 +77,55 @@ struct CommandLine {
 
 fn write_example_config() {
     let config = Config {
+        poll_interval : DEFAULT_POLL_INTERVAL,
         destinations : vec! {
             Box::new( DestinationLogConfig {} ),
+            Box::new( DestinationFileConfig::example_config()),
+            Box::new( DestinationSQLiteConfig::example_config()),
             Box::new( DestinationMQTTConfig::example_config()),
             Box::new( DestinationCloudwatchConfig::example_config()),
+            Box::new( DestinationPrometheusConfig::example_config()),
+            Box::new( DestinationInfluxDBConfig::example_config()),
+            Box::new( DestinationWebhookConfig::example_config()),
         },
         sources : vec! {
             Box::new( SourceConstantConfig::example_config()),
-            Box::new( SourceBLEConfig::example_config())
+            Box::new( SourceOneWireConfig::example_config()),
+            Box::new( SourceHwmonConfig::example_config()),
+            Box::new( SourceSystemConfig::example_config()),
+            Box::new( SourceBLEConfig::example_config()),
+            Box::new( SourceMQTTConfig::example_config())
         }
     };
 
 
-    let test_output : String = toml::to_string(&config).unwrap();
+    let test_output : String = match toml::to_string(&config) {
+        Ok(output) => output,
+        Err(error) => {
+            log::error!("Unable to serialise example configuration : {}", error);
+            return;
+        }
+    };
     let filename = "config.example.toml";
     println!("Writing example configuration to {}", filename);
-    std::fs::write(filename, test_output).unwrap();
+    if let Err(error) = std::fs::write(filename, test_output) {
+        log::error!("Unable to write {} : {}", filename, error);
+    }
+}
+
+fn print_history(object : &str, property : &str, from : &str, to : &Option<String>, database : &str, csv : bool) {
+    let now = chrono::Utc::now();
+    let history = parse_time(from, now)
+        .and_then( |from| Ok((from, match to { Some(to) => parse_time(to, now)?, None => now })) )
+        .and_then( |(from, to)| query(&open_database(database)?, object, property, from, to) );
+    match history {
+        Ok(rows) if csv => print!("{}", format_csv(&rows)),
+        Ok(rows) => print!("{}", format_table(&rows)),
+        Err(error) => {
+            log::error!("{}", error);
+            std::process::exit(1);
+        }
+    }
 }
 
 fn ctrl_channel() -> Result<crossbeam_channel::Receiver<()>, ctrlc::Error> {
@@ -98,22 +155,28 @@ async fn main() {
         log::trace!("Verbose mode!");
         log::trace!("Arguments : {:?}",args);
     }
+    // Given a database, a query doesn't need the config at all
+    if let Command::Query {object, property, from, to, database: Some(database), csv} = &args.cmd {
+        print_history(object, property, from, to, database, *csv);
+        return;
+    }
+
     log::info!("Using config from {}", args.config_file);
 
     let config_content = match std::fs::read_to_string(&args.config_file) {
         Ok(file) => file,
         Err(error) => {
-            log::error!("Error opening file \"{}\"", &args.config_file);
-            panic!("Error : {:?}",error)
+            log::error!("Error opening file \"{}\" : {}", &args.config_file, error);
+            std::process::exit(1);
         },
     };
 
      let config: Config = match toml::from_str(&config_content) {
         Ok(m) => m,
         Err(error) => {
-            log::error!("Error reading configuration file \"{}\"", &args.config_file);
-            write_example_config();            
-            panic!("Error : {:?}",error)
+            log::error!("Error reading configuration file \"{}\" : {}", &args.config_file, error);
+            write_example_config();
+            std::process::exit(1);
         },
      };
 
@@ -142,25 +205,77 @@ async fn main() {
             write_example_config();
         }
         Command::BLEScan{duration} => {
-            let mut x = BleManager::create();
+            let mut x = match BleManager::create() {
+                Ok(x) => x,
+                Err(error) => {
+                    log::error!("{}", error);
+                    std::process::exit(1);
+                }
+            };
             x.scan(ctrl_c_events, Duration::from_secs(*duration));
             x.list(DBADDR_MAX);
             x.shutdown();
         },
         Command::BLEConnect {id} => {
-            let mut x = BleManager::create();
             let address_to_find : btleplug::api::BDAddr = if id == "*" {
                 DBADDR_MAX
             } else {
-                id.parse().unwrap()
+                match id.parse() {
+                    Ok(address) => address,
+                    Err(error) => {
+                        log::error!("Bad address \"{}\" : {:?}", id, error);
+                        std::process::exit(1);
+                    }
+                }
+            };
+            let mut x = match BleManager::create() {
+                Ok(x) => x,
+                Err(error) => {
+                    log::error!("{}", error);
+                    std::process::exit(1);
+                }
             };
             x.connect_and_print_characteristics(ctrl_c_events, address_to_find).await;
 
             x.shutdown();
         }        
         Command::Run {} => {
-            let mut manager = Manager::create( config );
-            manager.run().await;
+            let mut config = config;
+            let mut config_content = config_content;
+            loop {
+                let mut manager = Manager::create( config );
+                if manager.run(ctrl_c_events.clone()).await != RunOutcome::Reload {
+                    break;
+                }
+                log::info!("Reloading config from {}", args.config_file);
+                let reloaded = std::fs::read_to_string(&args.config_file).map_err(Error::from)
+                    .and_then( |content| Ok((toml::from_str::<Config>(&content)?, content)) );
+                config = match reloaded {
+                    Ok((new_config, content)) => {
+                        config_content = content;
+                        new_config
+                    }
+                    Err(error) => {
+                        log::error!("Unable to reload \"{}\", keeping the previous configuration : {}", args.config_file, error);
+                        match toml::from_str(&config_content) {
+                            Ok(config) => config,
+                            Err(error) => {
+                                log::error!("Error reading configuration : {}", error);
+                                std::process::exit(1);
+                            }
+                        }
+                    }
+                };
+            }
+        }
+        Command::Query {object, property, from, to, database: _, csv} => {
+            match configured_path(&config_content) {
+                Some(database) => print_history(object, property, from, to, &database, *csv),
+                None => {
+                    log::error!("No sqlite destination in \"{}\", give --database", args.config_file);
+                    std::process::exit(1);
+                }
+            }
         }
 
     }